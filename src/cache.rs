@@ -3,6 +3,9 @@ use serde::{Serialize, Deserialize};
 use std::path::Path;
 use crate::names::Id;
 
+///Layout of the values this release writes, rows cached before the layout was recorded are version 0
+pub const VERSION: u32 = 1;
+
 pub struct Cache(Connection);
 impl Cache {
     pub fn new<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
@@ -24,22 +27,31 @@ impl Cache {
         let tx = conn.transaction_with_behavior(TransactionBehavior::Exclusive)?;
         tx.execute("CREATE TABLE if not exists Cache(
             key TEXT NOT NULL PRIMARY KEY,
-            value BLOB NOT NULL
+            value BLOB NOT NULL,
+            version INTEGER NOT NULL DEFAULT 0
         );", [])?;
+        let versioned = tx.query_row("SELECT COUNT(*) FROM pragma_table_info('Cache') WHERE name='version'", [], |r| r.get::<_, i64>(0))?;
+        if versioned == 0 {tx.execute("ALTER TABLE Cache ADD COLUMN version INTEGER NOT NULL DEFAULT 0", [])?;}
         tx.commit()?;
         Ok(Cache(conn))
     }
-    pub fn get<T: for<'a> Deserialize<'a>>(&self, key: &str) -> Result<Option<T>, Error> {
-        Ok(self.0.query_row(
-            &format!("SELECT value FROM Cache WHERE key='{key}'"),
-            [], |r| Ok(postcard::from_bytes(&r.get::<_, Vec<u8>>(0)?).ok()),
-        ).optional()?.flatten())
+    ///A value written before the current VERSION is decoded in the layout it was written in, O, and brought up
+    ///to date by migrate. Values that do not decode come back as None
+    pub fn get<T: for<'a> Deserialize<'a>, O: for<'a> Deserialize<'a>>(&self, key: &str, migrate: impl FnOnce(O) -> T) -> Result<Option<T>, Error> {
+        let row = self.0.query_row(
+            &format!("SELECT value, version FROM Cache WHERE key='{key}'"),
+            [], |r| Ok((r.get::<_, Vec<u8>>(0)?, r.get::<_, u32>(1)?)),
+        ).optional()?;
+        Ok(row.and_then(|(value, version)| match version {
+            VERSION => postcard::from_bytes(&value).ok(),
+            _ => postcard::from_bytes(&value).ok().map(migrate)
+        }))
     }
 
     pub fn insert<T: Serialize>(&mut self, key: &str, value: &T) -> Result<(), Error> {
         self.0.execute(
-            &format!("INSERT INTO Cache(key, value) VALUES ('{key}', ?1) ON CONFLICT DO UPDATE SET value=excluded.value;"),
-            [postcard::to_allocvec(value).unwrap()],
+            &format!("INSERT INTO Cache(key, value, version) VALUES ('{key}', ?1, ?2) ON CONFLICT DO UPDATE SET value=excluded.value, version=excluded.version;"),
+            (postcard::to_allocvec(value).unwrap(), VERSION),
         )?;
         Ok(())
     }
@@ -1,18 +1,55 @@
 use serde::{Serialize, Deserialize};
 
-use crate::names::{secp256k1::{Signed as KeySigned, SecretKey, Encrypted as KeyEncrypted}, Encrypted, Secret, Signed, Name, Id};
-use crate::storage::{Compare, Request, Response};
+use crate::names::{secp256k1::{Signed as KeySigned, SecretKey, PublicKey, KemKey, Encrypted as KeyEncrypted}, Encrypted, Secret, Signed, Signature, Name, Id, INBOX_ANNOUNCE};
+use crate::storage::{Authorized, Mailbox, Cursor, Page, MAX_PAGE, PRUNED, Request, Response};
+use crate::server::{Connection, Receiver, MAX_RETRY};
+use crate::stamp::{Stamp, Policy};
 use crate::Air;
 
 use crossfire::{MAsyncTx, AsyncTx, AsyncRx, mpsc, spsc};
 
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::sync::{Arc, Mutex};
+use tokio::task::JoinHandle;
+use tokio::time::{sleep, Duration};
+
 pub const CHANNEL: &str = "CHANNEL";
+pub const MAILBOX: &str = "MAILBOX";
+pub const CHECKPOINT: &str = "CHECKPOINT";
+pub const ANNOUNCE: &str = "ANNOUNCE";
+///Entries read ahead of the cursor while catching up, so history arrives at the speed of the connection
+pub const PIPELINE: u64 = 64;
+///How long each mailbox is published before senders are pointed at the next one
+pub const MAILBOX_EPOCH: u64 = 24 * 3_600_000_000_000;//1 day
 
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub enum Event {
     Head,
     Garbage,
     Data(Name, Vec<u8>, Option<Id>), 
+    ///A members snapshot of the state before the channels index, when set it replaces the history that was
+    ///skipped, otherwise it should match the history already read
+    Checkpoint(Name, Vec<u8>, bool),
+    ///A member re-keyed the channel and it starts over under the new key, members left out never see this.
+    ///Carries the request id when the re-key was ours
+    Forward(Option<Id>),
+}
+
+///Signed by the member then encrypted to its checkpoint slot, after the log key and position in the clear
+type Checkpoint = Signed<(u64, u64, Vec<u8>)>;//Position, Timestamp, State
+
+///What a member signs and encrypts to the channel
+#[derive(Serialize, Deserialize, Debug, Clone, Hash)]
+enum Record {
+    Data(Vec<u8>),
+    ///The channels new key, encrypted to each member that remains
+    Forward(Vec<Vec<u8>>),
+}
+
+#[derive(Debug)]
+enum Write {
+    Data(Vec<u8>),
+    Rekey(Vec<Name>),
 }
 
 #[derive(Debug)]
@@ -27,30 +64,56 @@ impl Stream {
 }
 
 #[derive(Clone, Debug)]
-pub struct Sink(MAsyncTx<spsc::List<(Id, Vec<u8>)>>);
+pub struct Sink(MAsyncTx<spsc::List<(Id, Write)>>);
 impl Sink {
     pub async fn write(&self, data: Vec<u8>) -> Id {
         let id = Id::random();
-        self.0.send((id, data)).await.unwrap();
+        self.0.send((id, Write::Data(data))).await.unwrap();
         id
     }
     pub fn write_sync(&self, data: Vec<u8>) -> Id {
         let id = Id::random();
-        self.0.clone().try_send((id, data)).unwrap();
+        self.0.clone().try_send((id, Write::Data(data))).unwrap();
+        id
+    }
+    ///Moves the channel to a fresh key that only the members listed, and us, can follow. Anyone else holding
+    ///the old key keeps reading a channel nobody writes to anymore
+    pub fn rekey(&self, members: Vec<Name>) -> Id {
+        let id = Id::random();
+        self.0.clone().try_send((id, Write::Rekey(members))).unwrap();
         id
     }
 }
 
 #[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Copy, Debug, Default)]
 pub struct Channel {
-    //pub servers: Vec<Name>,
+    ///Where the log is kept, every member has to use the same one. None is our own home server, only for
+    ///channels nobody else reads
+    pub server: Option<Name>,
     pub key: SecretKey,
     pub index: u64,
     pub timestamp: u64,
+    ///Still reading the slot per index channels were kept in before logs, the index counts slots until one is
+    ///empty and then starts over in the log. Only set on channels restored from a cache written before logs
+    pub legacy: bool,
+    ///Slot of the newest checkpoint seen, every one before it is older so scans for a newer one start here
+    pub checkpoint_slot: u64,
+}
+
+///How a Channel was cached before it named its server and moved to a log, it was kept on the home server
+#[derive(Deserialize)]
+pub struct LegacyChannel {key: SecretKey, index: u64, timestamp: u64}
+impl From<LegacyChannel> for Channel {
+    fn from(channel: LegacyChannel) -> Self {
+        Channel{server: None, key: channel.key, index: channel.index, timestamp: channel.timestamp, legacy: true, checkpoint_slot: 0}
+    }
 }
 
 impl Channel {
-    pub fn new(key: SecretKey) -> Self {Channel{key, index: 0, timestamp: 0}}
+    pub fn new(key: SecretKey) -> Self {Channel{server: None, key, index: 0, timestamp: 0, legacy: false, checkpoint_slot: 0}}
+    pub fn at(key: SecretKey, server: Name) -> Self {Channel{server: Some(server), ..Self::new(key)}}
+
+    fn server(&self, air: &Air) -> Name {self.server.unwrap_or(air.config.server)}
 
     ///It is assumed that the channels path is equal to the path of the secret
     ///Its up to you to ensure the secret is at the correct path for this channel
@@ -62,147 +125,558 @@ impl Channel {
         let channel = self;
         air.handle.spawn(async move {
             let mut head = false;
-            let server = Name::orange_me();
-            let key = self.key.derive(&[Id::hash(&server)]);
+            let server = self.server(&air);
+            let (mut current, (mut key, mut public, mut kem)) = (self.key, Self::log(&air, server, &self.key));
             let connection = air.purser.connect(server).await.unwrap();
 
-            let mut request: Option<(Vec<u8>, Vec<u8>, Id)> = None;
-            loop {
-                let key = key.derive(&[Id::hash(&self.index)]);
-                let public = key.public_key();
-                match &mut request {
-                    Some(_) => {},
-                    none => {*none = rx.try_recv().ok().map(|tuple: (Id, Vec<u8>)|{
-                        (tuple.1.clone(), postcard::to_allocvec(&Signed::new(&secret, tuple.1)).unwrap(), tuple.0)
-                    });}
-                }
+            //A fresh channel starts at the latest checkpoint, or replays everything and checks it on the way past
+            let mut checkpoint = match self.index {
+                0 => Self::checkpoints(&air, &connection, server, &key, &secret, self.checkpoint_slot).await.1.map(|(slot, checkpoint)| {
+                    self.checkpoint_slot = slot;
+                    checkpoint
+                }),
+                _ => None
+            };
+            if !air.config.verify_checkpoints && let Some(Signed{signer, payload: (position, timestamp, state), ..}) = checkpoint.take() {
+                (self.index, self.timestamp, self.legacy) = (position, timestamp, false);
+                tx.send((self, Event::Checkpoint(signer, state, true))).await.unwrap();
+            }
 
-                if let Some((signature, time, key_sig, payload)) = match request.as_ref() {
-                    Some((_, data, _)) => {
-                        let encrypted = postcard::to_allocvec(&public.encrypt(data.clone())).unwrap();
-                        let hash = Id::hash(&encrypted);
-                        let response = connection.send(Request::Create(KeySigned::new(&key, encrypted))).await.recv().await;
-                        match response{
-                            Response::Create(signature, time) => {
-                                let identity = air.resolver.resolve(server, Some(time)).await;
-                                if signature.verify(&identity, &[], Id::hash(&(public, time, hash))).is_ok() 
-                                && self.timestamp < time {
-                                    self.timestamp = time;
-                                    self.index += 1;
-                                    tx.send((self, request.take().map(|(d, _, rid)| Event::Data(secret.name(), d, Some(rid))).expect("Bad Air Server"))).await.unwrap()
-                                } else {panic!("Bad Air Server");}
-                                None
-                            },
-                            Response::Read(signature, time, Some((key_sig, payload))) => Some((signature, time, key_sig, payload)),
-                            _ => {panic!("Bad Air Server")}
-                        }
+            while self.legacy {
+                let slot = key.derive(&[Id::hash(&self.index)]);
+                let public = slot.public_key();
+                let Response::Read(signature, time, data) = connection.send(Request::Read(public, false)).await.recv().await else {
+                    panic!("Bad Air Server");
+                };
+                let identity = air.resolver.resolve(server, Some(time)).await;
+                let Some((key_sig, payload)) = data else {
+                    if signature.verify(&identity, &[], Id::hash(&(public, time, Id::MIN))).is_err() {panic!("Bad Air Server");}
+                    (self.index, self.legacy) = (0, false);
+                    break;
+                };
+                let hash = Id::hash(&payload);
+                if signature.verify(&identity, &[], Id::hash(&(public, time, hash))).is_err() || key_sig.verify(&public, hash).is_err() {
+                    panic!("Bad Air Server");
+                }
+                self.index += 1;
+                //Slots were written one at a time so each has to be newer than the last
+                let signed = postcard::from_bytes::<KeyEncrypted>(&payload).ok().and_then(|e| slot.decrypt(e).ok())
+                    .and_then(|d| postcard::from_bytes::<Signed<Vec<u8>>>(&d).ok()).filter(|_| time > self.timestamp);
+                let event = match signed {
+                    Some(signed) if signed.verify(&air.resolver.resolve(signed.signer, Some(time)).await, secret.path()).is_ok() => {
+                        Event::Data(signed.signer, signed.payload, None)
                     },
-                    None => {
-                        let mut subscription = connection.clone().send(Request::Read(public, true)).await;
-                        loop {tokio::select! {
-                            Response::Read(signature, time, data) = subscription.recv() => {
+                    _ => {println!("bad legacy slot"); Event::Garbage}
+                };
+                self.timestamp = self.timestamp.max(time);
+                tx.send((self, event)).await.unwrap();
+            }
+
+            //Our last write, emitted once every entry before its index has been read
+            let mut appended: Option<(u64, u64, Record, Id)> = None;
+            //Reads in flight for the entries from the cursor on, until one comes back empty
+            let mut ahead: VecDeque<Receiver> = VecDeque::new();
+            let mut behind = true;
+            'read: loop {
+                //Followed a forward pointer, a write of ours after it would never be read so it moves with us
+                if self.key != current {
+                    current = self.key;
+                    (key, public, kem) = Self::log(&air, server, &self.key);
+                    (ahead, behind, checkpoint) = (VecDeque::new(), true, None);
+                    if let Some((_, _, record, rid)) = appended.take() {
+                        appended = Some(Self::append(&air, &connection, server, &key, &secret, kem.as_ref(), rid, record).await);
+                    }
+                }
+                if let Some(Signed{signer, payload: (_, _, state), ..}) = checkpoint.take_if(|c| c.payload.0 == self.index) {
+                    tx.send((self, Event::Checkpoint(signer, state, false))).await.unwrap();
+                }
+                if appended.is_none() && let Ok((rid, write)) = rx.try_recv() {
+                    let record = Self::record(&air, write).await;
+                    appended = Some(Self::append(&air, &connection, server, &key, &secret, kem.as_ref(), rid, record).await);
+                }
+                if appended.as_ref().is_some_and(|(index, ..)| *index == self.index) {
+                    let (_, time, record, rid) = appended.take().unwrap();
+                    ahead.pop_front();
+                    self.index += 1;
+                    self.timestamp = self.timestamp.max(time);
+                    let event = match record {
+                        Record::Data(data) => Event::Data(secret.name(), data, Some(rid)),
+                        Record::Forward(forward) => self.follow(&air, forward, Some(rid))
+                    };
+                    tx.send((self, event)).await.unwrap();
+                    continue;
+                }
+
+                //Catching up, or reading the entries stored before our write
+                if let Some((signature, time, key_sig, payload)) = if behind || appended.is_some() {
+                    let end = appended.as_ref().map(|(index, ..)| *index).unwrap_or(u64::MAX);
+                    while (ahead.len() as u64) < PIPELINE && self.index + (ahead.len() as u64) < end {
+                        let next = self.index + ahead.len() as u64;
+                        ahead.push_back(connection.send(Request::Entry(public, next, false)).await);
+                    }
+                    match ahead.pop_front().expect("Bad Air Server").recv().await {
+                        Response::Entry(index, signature, time, Some((key_sig, payload))) if index == self.index => Some((signature, time, key_sig, payload)),
+                        //Either the end of the log, or a read that raced an entry before our write
+                        Response::Entry(index, _, _, None) if index == self.index => {
+                            behind = false;
+                            ahead.clear();
+                            None
+                        },
+                        Response::Pruned(pruned, signature, time) => {
+                            let before = self;
+                            let event = self.skip(&air, &connection, server, &key, &secret, (pruned, signature, time)).await;
+                            checkpoint = None;
+                            ahead.clear();
+                            //Our write was pruned along with the rest so the checkpoint already has it, its data is
+                            //confirmed ahead of the checkpoint so it leaves the pending queue without being applied
+                            //twice, a forward is followed once the checkpoint is in place
+                            match appended.take_if(|(index, ..)| *index < self.index) {
+                                Some((_, _, Record::Data(data), rid)) => {
+                                    tx.send((before, Event::Data(secret.name(), data, Some(rid)))).await.unwrap();
+                                    tx.send((self, event)).await.unwrap();
+                                },
+                                Some((_, _, Record::Forward(forward), rid)) => {
+                                    tx.send((self, event)).await.unwrap();
+                                    let event = self.follow(&air, forward, Some(rid));
+                                    tx.send((self, event)).await.unwrap();
+                                },
+                                None => tx.send((self, event)).await.unwrap()
+                            }
+                            continue;
+                        },
+                        _ => {panic!("Bad Air Server")}
+                    }
+                } else {
+                    let mut subscription = connection.clone().send(Request::Entry(public, self.index, true)).await;
+                    loop {tokio::select! {
+                        response = subscription.recv() => match response {
+                            Response::Entry(index, signature, time, data) => {
+                                if index != self.index {panic!("Bad Air Server");}
                                 if let Some((key_sig, payload)) = data {
                                     break Some((signature, time, key_sig, payload));
                                 } else if !head {
                                     let identity = air.resolver.resolve(server, Some(time)).await;
-                                    if signature.verify(&identity, &[], Id::hash(&(public, time, Id::MIN))).is_err() {
+                                    if signature.verify(&identity, &[], Id::hash(&(public, index, time, Id::MIN))).is_err() {
                                         panic!("Bad Air Server");
                                     }
                                     head = true;
                                     tx.send((self, Event::Head)).await.unwrap();
                                 }
                             },
-                            Ok((rid, d)) = rx.recv() => {
-                                request = Some((d.clone(), postcard::to_allocvec(&Signed::new(&secret, d)).unwrap(), rid));
-                                break None;
-                            }
-                            else => {panic!("Bad Air Server")}
-                        }}
-                    }
+                            Response::Pruned(pruned, signature, time) => {
+                                let event = self.skip(&air, &connection, server, &key, &secret, (pruned, signature, time)).await;
+                                checkpoint = None;
+                                tx.send((self, event)).await.unwrap();
+                                continue 'read;
+                            },
+                            _ => {panic!("Bad Air Server")}
+                        },
+                        Ok((rid, write)) = rx.recv() => {
+                            let record = Self::record(&air, write).await;
+                            appended = Some(Self::append(&air, &connection, server, &key, &secret, kem.as_ref(), rid, record).await);
+                            break None;
+                        }
+                        else => {panic!("Bad Air Server")}
+                    }}
                 } {
-                    self.index += 1;
                     let hash = Id::hash(&payload);
                     let identity = air.resolver.resolve(server, Some(time)).await;
-                    if signature.verify(&identity, &[], Id::hash(&(public, time, hash))).is_ok()
+                    if signature.verify(&identity, &[], Id::hash(&(public, self.index, time, hash))).is_ok()
                     && key_sig.verify(&public, hash).is_ok() {
-                        let result = if time > self.timestamp {
-                            self.timestamp = time;
-                            if let Some(signed) = postcard::from_bytes::<KeyEncrypted>(&payload).ok().and_then(|e| key.decrypt(e).ok().and_then(|d| postcard::from_bytes::<Signed<Vec<u8>>>(&d).ok())) {
-                                let identity = air.resolver.resolve(signed.signer, Some(time)).await;
-                                if signed.verify(&identity, secret.path()).is_ok() {
-                                    Some((signed.signer, signed.payload))
-                                } else {println!("bad signature"); None}
-                            } else {println!("bad encryption/serialization"); None}
-                        } else {println!("bad time"); None};
-                        tx.send((self, result.map(|(a, b)| Event::Data(a, b, None)).unwrap_or(Event::Garbage))).await.unwrap();
+                        self.index += 1;
+                        self.timestamp = self.timestamp.max(time);
+                        let result = if let Some(signed) = postcard::from_bytes::<KeyEncrypted>(&payload).ok().and_then(|e| key.decrypt(e).ok().and_then(|d| postcard::from_bytes::<Signed<Record>>(&d).ok())) {
+                            let identity = air.resolver.resolve(signed.signer, Some(time)).await;
+                            if signed.verify(&identity, secret.path()).is_ok() {
+                                Some((signed.signer, signed.payload))
+                            } else {println!("bad signature"); None}
+                        } else {println!("bad encryption/serialization"); None};
+                        let event = match result {
+                            Some((signer, Record::Data(data))) => Event::Data(signer, data, None),
+                            Some((_, Record::Forward(forward))) => self.follow(&air, forward, None),
+                            None => Event::Garbage
+                        };
+                        tx.send((self, event)).await.unwrap();
                     } else {panic!("Bad Air Server");}
                 }
             }
         });
         (Stream(channel, read), Sink(write))
     }
+
+    ///The key of this channels log on the server, with the keys entries are checked and encrypted with
+    fn log(air: &Air, server: Name, key: &SecretKey) -> (SecretKey, PublicKey, Option<KemKey>) {
+        let key = key.derive(&[Id::hash(&server)]);
+        let (public, kem) = (key.public_key(), air.config.hybrid.then(|| key.kem_key()));
+        (key, public, kem)
+    }
+
+    ///Picks the fresh key for a rekey and encrypts it to each remaining member, including us
+    async fn record(air: &Air, write: Write) -> Record {match write {
+        Write::Data(data) => Record::Data(data),
+        Write::Rekey(members) => {
+            let key = postcard::to_allocvec(&SecretKey::new()).unwrap();
+            let mut forward = vec![];
+            for name in members.into_iter().chain([air.name]).collect::<BTreeSet<_>>() {
+                let identity = air.resolver.resolve(name, None).await;
+                forward.push(postcard::to_allocvec(&identity.encrypt_padded(&[], key.clone(), &air.config.padding)).unwrap());
+            }
+            Record::Forward(forward)
+        }
+    }}
+
+    ///Moves onto the key the forward pointer carries for us, starting over at its first entry
+    fn follow(&mut self, air: &Air, forward: Vec<Vec<u8>>, rid: Option<Id>) -> Event {
+        let key = forward.iter().find_map(|e| postcard::from_bytes::<Encrypted>(e).ok()
+            .and_then(|e| air.secret.decrypt(e).ok()).and_then(|d| postcard::from_bytes::<SecretKey>(&d).ok()));
+        match key {
+            Some(key) => {
+                (self.key, self.index, self.legacy, self.checkpoint_slot) = (key, 0, false, 0);
+                Event::Forward(rid)
+            },
+            None => {println!("Removed From Channel"); Event::Garbage}
+        }
+    }
+
+    fn checkpoint_key(key: &SecretKey, n: u64) -> SecretKey {key.derive(&[Id::hash(CHECKPOINT), Id::hash(&n)])}
+
+    ///Counts the checkpoint slots from the given one a page at a time until one is empty, returning the count
+    ///and the slot of the one a member signed at the furthest position. Members race to fill the next slot so
+    ///a later slot can hold an older checkpoint
+    async fn checkpoints(air: &Air, connection: &Connection, server: Name, key: &SecretKey, secret: &Secret, from: u64) -> (u64, Option<(u64, Checkpoint)>) {
+        let mut latest: Option<(u64, Checkpoint)> = None;
+        for start in (from..).step_by(MAX_PAGE as usize) {
+            let keys = (start..start + MAX_PAGE as u64).map(|n| Self::checkpoint_key(key, n)).collect::<Vec<_>>();
+            let Response::ReadMany(reads) = connection.send(Request::ReadMany(keys.iter().map(|k| k.public_key()).collect())).await.recv().await else {
+                panic!("Bad Air Server");
+            };
+            for ((n, slot), (signature, time, data)) in (start..).zip(&keys).zip(reads) {
+                let Some((key_sig, payload)) = data else {return (n, latest)};
+                let (public, hash) = (slot.public_key(), Id::hash(&payload));
+                let identity = air.resolver.resolve(server, Some(time)).await;
+                if signature.verify(&identity, &[], Id::hash(&(public, time, hash))).is_err() || key_sig.verify(&public, hash).is_err() {
+                    panic!("Bad Air Server");
+                }
+                //The log and position in the clear, for the server to check prunes against, have to match the checkpoint
+                let checkpoint = postcard::from_bytes::<(PublicKey, u64, KeyEncrypted)>(&payload).ok().filter(|(log, ..)| *log == key.public_key())
+                    .and_then(|(_, position, e)| slot.decrypt(e).ok().and_then(|d| postcard::from_bytes::<Checkpoint>(&d).ok()).filter(|c| c.payload.0 == position));
+                if let Some(checkpoint) = checkpoint
+                && checkpoint.verify(&air.resolver.resolve(checkpoint.signer, Some(time)).await, secret.path()).is_ok() {
+                    if latest.as_ref().is_none_or(|(_, l)| checkpoint.payload.0 > l.payload.0) {latest = Some((n, checkpoint));}
+                } else {println!("bad checkpoint");}
+            }
+        }
+        unreachable!()
+    }
+
+    ///Publishes the state as of this channels position for new members to start from, the state is whatever
+    ///they need to rebuild it without the entries before the position
+    pub fn checkpoint(self, air: Air, secret: Secret, state: Vec<u8>) -> JoinHandle<()> {
+        let secret = secret.derive(&[Id::hash(CHANNEL)]);
+        air.handle.clone().spawn(async move {
+            //Positions are in the log, the legacy slots are checkpointed along with it once it is reached
+            if self.legacy {return}
+            let server = self.server(&air);
+            let key = self.key.derive(&[Id::hash(&server)]);
+            let connection = air.purser.connect(server).await.unwrap();
+            let (mut n, latest) = Self::checkpoints(&air, &connection, server, &key, &secret, self.checkpoint_slot).await;
+            if latest.is_some_and(|(_, c)| c.payload.0 >= self.index) {return}
+
+            let checkpoint: Checkpoint = Signed::new(&secret, (self.index, self.timestamp, state));
+            let checkpoint = postcard::to_allocvec(&checkpoint).unwrap();
+            loop {
+                let slot = Self::checkpoint_key(&key, n);
+                let kem = air.config.hybrid.then(|| slot.kem_key());
+                let encrypted = slot.public_key().encrypt_with(checkpoint.clone(), &air.config.padding, kem.as_ref());
+                let encrypted = postcard::to_allocvec(&(key.public_key(), self.index, encrypted)).unwrap();
+                match connection.send(Request::Create(KeySigned::new(&slot, encrypted))).await.recv().await {
+                    Response::Create(..) => break,
+                    //Another member checkpointed first
+                    Response::Read(..) => n += 1,
+                    response => {println!("Checkpoint Failed: {response:?}"); break}
+                }
+            }
+        })
+    }
+
+    ///Lets the server delete the entries before the latest checkpoint, anyone reading them afterwards is moved
+    ///on to the checkpoint instead
+    pub fn prune(self, air: Air, secret: Secret) -> JoinHandle<()> {
+        let secret = secret.derive(&[Id::hash(CHANNEL)]);
+        air.handle.clone().spawn(async move {
+            let server = self.server(&air);
+            let key = self.key.derive(&[Id::hash(&server)]);
+            let connection = air.purser.connect(server).await.unwrap();
+            let Some((slot, latest)) = Self::checkpoints(&air, &connection, server, &key, &secret, self.checkpoint_slot).await.1 else {return};
+            let covered = (latest.payload.0, Self::checkpoint_key(&key, slot).public_key());
+            match connection.send(Request::Prune(KeySigned::new(&key, covered))).await.recv().await {
+                Response::Pruned(..) => {},
+                response => println!("Prune Failed: {response:?}")
+            }
+        })
+    }
+
+    ///Moves past the entries the server says were pruned, to the latest checkpoint if it covers them
+    async fn skip(&mut self, air: &Air, connection: &Connection, server: Name, key: &SecretKey, secret: &Secret, pruned: (u64, Signature, u64)) -> Event {
+        let (pruned, signature, time) = pruned;
+        let identity = air.resolver.resolve(server, Some(time)).await;
+        if pruned <= self.index || signature.verify(&identity, &[], Id::hash(&(key.public_key(), pruned, time, Id::hash(PRUNED)))).is_err() {
+            panic!("Bad Air Server");
+        }
+        match Self::checkpoints(air, connection, server, key, secret, self.checkpoint_slot).await.1 {
+            Some((slot, Signed{signer, payload: (position, timestamp, state), ..})) if position >= pruned => {
+                (self.index, self.checkpoint_slot) = (position, slot);
+                self.timestamp = self.timestamp.max(timestamp);
+                Event::Checkpoint(signer, state, true)
+            },
+            _ => {
+                println!("History Pruned Without A Checkpoint");
+                self.index = pruned;
+                Event::Garbage
+            }
+        }
+    }
+
+    ///Appends the signed and encrypted record to the log, returning the index and timestamp the server gave it
+    #[allow(clippy::too_many_arguments)]
+    async fn append(air: &Air, connection: &Connection, server: Name, key: &SecretKey, secret: &Secret, kem: Option<&KemKey>, rid: Id, record: Record) -> (u64, u64, Record, Id) {
+        let signed = postcard::to_allocvec(&Signed::new(secret, record.clone())).unwrap();
+        let public = key.public_key();
+        let encrypted = postcard::to_allocvec(&public.encrypt_with(signed, &air.config.padding, kem)).unwrap();
+        let hash = Id::hash(&encrypted);
+        match connection.send(Request::Log(KeySigned::new(key, encrypted))).await.recv().await {
+            Response::Logged(index, signature, time) => {
+                let identity = air.resolver.resolve(server, Some(time)).await;
+                if signature.verify(&identity, &[], Id::hash(&(public, index, time, hash))).is_err() {panic!("Bad Air Server");}
+                (index, time, record, rid)
+            },
+            _ => {panic!("Bad Air Server")}
+        }
+    }
 }
 
+///Sealed sender envelope, signed by the sender over the recipient and payload then encrypted to the recipient
+///so the server never learns who sent it
+type Sealed = Signed<(Name, Vec<u8>)>;
+
+///The anonymous connection each epochs mailbox is read over, acks for a mailbox go over the same one so the
+///server cannot link mailboxes by the connection acking them
+type Readers = Arc<Mutex<BTreeMap<u64, Connection>>>;
+
 #[derive(Debug)]
-pub struct InboxHandler(Inbox, AsyncRx<spsc::List<(u64, Option<Vec<u8>>)>>);
+pub struct InboxHandler(Inbox, AsyncRx<mpsc::List<(u64, Cursor, Option<(Name, Vec<u8>)>)>>, Air, Readers);
 impl InboxHandler {
     pub fn inbox(&self) -> &Inbox {&self.0}
 
-    pub async fn read(&mut self) -> (u64, Option<Vec<u8>>) {
-        let (time, data) = self.1.recv().await.unwrap();
-        self.0.0 = time;
-        (time, data)
+    ///Lets the home server delete everything read so far, call once the inbox position has been persisted
+    pub fn ack(&self) {
+        let (air, cursors, readers) = (self.2.clone(), self.0.0.clone(), self.3.clone());
+        air.handle.clone().spawn(async move {
+            let home = air.config.server;
+            for (epoch, cursor) in cursors {
+                let reader = readers.lock().unwrap().get(&epoch).cloned();
+                let conn = match reader {
+                    Some(conn) => conn,
+                    None => air.purser.anonymous(home).await.unwrap()
+                };
+                match conn.send(Request::Ack(Authorized::Signed(KeySigned::new(&Inbox::mailbox(&air.secret, epoch), cursor)))).await.recv().await {
+                    Response::Ack(acked) if acked == cursor => {},
+                    response => {println!("Ack Failed: {response:?}");}
+                }
+            }
+        });
+    }
+
+    ///Returns the verified sender with each payload, None if the missive could not be opened
+    pub async fn read(&mut self) -> (Cursor, Option<(Name, Vec<u8>)>) {
+        let (epoch, cursor, data) = self.1.recv().await.unwrap();
+        let current = self.2.now() / MAILBOX_EPOCH;
+        self.0.0.insert(epoch, cursor);
+        self.0.0.retain(|epoch, _| *epoch + 1 >= current);
+        (cursor, data)
     }
 
     pub fn send(air: Air, name: Name, location: Vec<u8>) {
         air.handle.spawn(async move {
             let identity = air.resolver.resolve(name, None).await;
-            let home = *identity.servers().first().unwrap();
-            let conn = air.purser.connect(home).await.unwrap();
-            conn.send(Request::Send(name, postcard::to_allocvec(&identity.encrypt(&[], location)).unwrap())).await;
+            let Some(home) = identity.servers().first().copied() else {println!("Send Rejected: {name} Has No Home Server"); return};
+            let conn = air.purser.anonymous(home).await.unwrap();
+            let (mailbox, policy) = match Inbox::locate(&air, &conn, home, name).await {
+                Ok(located) => located,
+                Err(e) => {println!("Send Rejected: {e}"); return}
+            };
+            let sealed: Sealed = Signed::new(&air.secret, (name, location));
+            let payload = postcard::to_allocvec(&identity.encrypt_padded(&[], postcard::to_allocvec(&sealed).unwrap(), &air.config.padding)).unwrap();
+            //A throttled send is stamped again, the old stamp may have expired while waiting
+            loop {
+                let stamp = match Stamp::sealed(&air.secret, mailbox, &policy, &payload, air.purser.now(&home)).await {
+                    Ok(stamp) => stamp,
+                    Err(e) => {println!("Send Rejected: {e}"); return}
+                };
+                match conn.send(Request::Send(mailbox, payload.clone(), stamp)).await.recv().await {
+                    Response::Create(..) => break,
+                    Response::Throttled(wait) if wait <= MAX_RETRY => sleep(Duration::from_nanos(wait)).await,
+                    response => {println!("Send Rejected: {response:?}"); break}
+                }
+            }
         });
     }
 }
 
-#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default)]
-pub struct Inbox(u64);
+///Missives are delivered to a mailbox derived from the Secret for each epoch so the server only sees unrelated
+///keys, the previous epochs mailbox is still read for senders that located it just before the rotation.
+///The current mailbox is announced on a log on the home server whose key is derived from the Names own and
+///published on its identity, so nothing else the Name signs can pass for an announcement
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct Inbox(BTreeMap<u64, Cursor>);//Epoch, Cursor
 impl Inbox {
-    pub fn start(mut self, air: Air) -> InboxHandler {
-        let (tx, rx): (AsyncTx<_>, _) = spsc::build(spsc::List::new());
-
-        air.handle.spawn(async move { loop {
-            let identity = air.resolver.resolve(air.name, None).await;
-            let home = *identity.servers().first().unwrap();
-            let conn = air.purser.connect(home).await.unwrap();
-            match conn.send(Request::Receive(Signed::new(&air.secret, (Compare::Greater, self.0)))).await.recv().await {
-                Response::Inbox(received) => {
-                    let home_identity = air.resolver.resolve(home, None).await;
-                    for (signature, timestamp, data) in received {
-                        if signature.verify(&home_identity, &[], Id::hash(&(air.name, timestamp, &data))).is_ok() && timestamp > self.0 {
-                            self.0 = timestamp;
-                            let data = postcard::from_bytes::<Encrypted>(&data).ok().and_then(|d| air.secret.decrypt(d).ok());
-                            tx.send((timestamp, data)).await.unwrap();
+    fn mailbox(secret: &Secret, epoch: u64) -> SecretKey {secret.derive(&[Id::hash(MAILBOX), Id::hash(&epoch)]).harden()}
+
+    fn announcer(secret: &Secret) -> SecretKey {secret.identity_key().derive(&[Id::hash(ANNOUNCE)])}
+
+    pub fn start(self, air: Air) -> InboxHandler {
+        let (tx, rx): (MAsyncTx<_>, _) = mpsc::build(mpsc::List::new());
+
+        let (handler, inbox, connections) = (air.clone(), self.clone(), Readers::default());
+        let readers_connections = connections.clone();
+        air.handle.clone().spawn(async move {
+            let mut readers: BTreeMap<u64, JoinHandle<()>> = BTreeMap::new();
+            air.resolver.publish(&air.secret, INBOX_ANNOUNCE, Self::announcer(&air.secret).public_key().to_string()).await;
+            loop {
+                let current = air.now() / MAILBOX_EPOCH;
+                let (identity, home) = (air.resolver.resolve(air.name, None).await, air.config.server);
+                for epoch in [current.saturating_sub(1), current] {
+                    if readers.contains_key(&epoch) {continue}
+                    let key = Self::mailbox(&air.secret, epoch);
+                    let conn = air.purser.anonymous(home).await.unwrap();
+                    //The slot is written once, a changed difficulty or allowlist is only enforced from the next epoch
+                    let policy = postcard::to_allocvec(&Policy::of(&identity)).unwrap();
+                    match conn.send(Request::Create(KeySigned::new(&key, policy))).await.recv().await {
+                        Response::Create(..) | Response::Read(..) => {},
+                        response => {panic!("Bad Air Server: {response:?}");}
+                    }
+                    let cursor = self.0.get(&epoch).copied().unwrap_or_default();
+                    readers_connections.lock().unwrap().insert(epoch, conn.clone());
+                    readers.insert(epoch, air.handle.spawn(Self::receive(air.clone(), conn, home, key, epoch, cursor, tx.clone())));
+                }
+                Self::announce(&air, home, current).await;
+
+                readers.retain(|epoch, reader| {
+                    if *epoch + 1 < current {reader.abort();}
+                    *epoch + 1 >= current
+                });
+                readers_connections.lock().unwrap().retain(|epoch, _| *epoch + 1 >= current);
+                sleep(Duration::from_nanos(((current + 1) * MAILBOX_EPOCH).saturating_sub(air.now()))).await;
+            }
+        });
+        InboxHandler(inbox, rx, handler, connections)
+    }
+
+    ///Appends the epochs mailbox to the announcement log and prunes the ones before it, so the floor of the
+    ///log is always the latest announcement. Each announcement is a checkpoint of the log on its own, the slot
+    ///only records its index for the server to allow the prune
+    async fn announce(air: &Air, home: Name, epoch: u64) {
+        let key = &Self::announcer(&air.secret);
+        let conn = air.purser.connect(home).await.unwrap();
+        let announcement = postcard::to_allocvec(&(epoch, Self::mailbox(&air.secret, epoch).public_key())).unwrap();
+        match conn.send(Request::Log(KeySigned::new(key, announcement))).await.recv().await {
+            Response::Logged(index, ..) => {
+                let slot = Channel::checkpoint_key(key, index);
+                let _ = conn.send(Request::Create(KeySigned::new(&slot, postcard::to_allocvec(&(key.public_key(), index)).unwrap()))).await.recv().await;
+                if !matches!(conn.send(Request::Prune(KeySigned::new(key, (index, slot.public_key())))).await.recv().await, Response::Pruned(..)) {
+                    println!("Old Mailbox Announcements Not Pruned");
+                }
+            },
+            response => {panic!("Bad Air Server: {response:?}");}
+        }
+    }
+
+    ///Reads the latest mailbox the Name announced on its home server along with the Policy it was opened with,
+    ///checking the server signed every step and the announcement was signed with the key the Name published
+    pub(crate) async fn locate(air: &Air, conn: &Connection, home: Name, name: Name) -> Result<(Mailbox, Policy), String> {
+        let identity = air.resolver.resolve(name, None).await;
+        let log = identity.inbox_announce().ok_or(format!("{name} Has Not Started An Inbox"))?;
+        let (mut index, mut latest) = (0, None);
+        loop {match conn.send(Request::Entry(log, index, false)).await.recv().await {
+            Response::Pruned(pruned, signature, time) if pruned > index => {
+                let identity = air.resolver.resolve(home, Some(time)).await;
+                signature.verify(&identity, &[], Id::hash(&(log, pruned, time, Id::hash(PRUNED)))).map_err(|_| "Bad Air Server")?;
+                index = pruned;
+            },
+            Response::Entry(i, signature, time, Some((key_signature, payload))) if i == index => {
+                let (identity, hash) = (air.resolver.resolve(home, Some(time)).await, Id::hash(&payload));
+                signature.verify(&identity, &[], Id::hash(&(log, index, time, hash))).map_err(|_| "Bad Air Server")?;
+                key_signature.verify(&log, hash).map_err(|_| "Bad Air Server")?;
+                latest = postcard::from_bytes::<(u64, Mailbox)>(&payload).ok();
+                index += 1;
+            },
+            Response::Entry(i, signature, time, None) if i == index => {
+                let identity = air.resolver.resolve(home, Some(time)).await;
+                signature.verify(&identity, &[], Id::hash(&(log, index, time, Id::MIN))).map_err(|_| "Bad Air Server")?;
+                break;
+            },
+            response => Err(format!("Bad Air Server: {response:?}"))?
+        }}
+        let (epoch, mailbox) = latest.ok_or(format!("{name} Has Not Announced A Mailbox"))?;
+        //The previous epochs mailbox is still read, anything older may have been abandoned
+        if epoch + 1 < air.now() / MAILBOX_EPOCH {Err(format!("{name} Announced An Expired Mailbox"))?}
+
+        match conn.send(Request::Read(mailbox, false)).await.recv().await {
+            Response::Read(signature, time, Some((key_signature, payload))) => {
+                let (identity, hash) = (air.resolver.resolve(home, Some(time)).await, Id::hash(&payload));
+                signature.verify(&identity, &[], Id::hash(&(mailbox, time, hash))).map_err(|_| "Bad Air Server")?;
+                key_signature.verify(&mailbox, hash).map_err(|_| "Bad Air Server")?;
+                let policy = postcard::from_bytes(&payload).map_err(|_| "Invalid Mailbox Policy")?;
+                Ok((mailbox, policy))
+            },
+            response => Err(format!("Mailbox Not Opened: {response:?}"))
+        }
+    }
+
+    async fn receive(air: Air, conn: Connection, home: Name, key: SecretKey, epoch: u64, mut cursor: Cursor, tx: MAsyncTx<mpsc::List<(u64, Cursor, Option<(Name, Vec<u8>)>)>>) {
+        let mailbox = key.public_key();
+        let home_identity = air.resolver.resolve(home, None).await;
+        loop {
+            let mut pages = conn.send(Request::Receive(Authorized::Signed(KeySigned::new(&key, Page{after: cursor, limit: MAX_PAGE})))).await;
+            loop {match pages.recv().await {
+                Response::Inbox(received, more) => {
+                    for (c, (signature, timestamp, data)) in received {
+                        if signature.verify(&home_identity, &[], Id::hash(&(mailbox, timestamp, &data))).is_ok() && c > cursor {
+                            cursor = c;
+                            let sealed = postcard::from_bytes::<Encrypted>(&data).ok().and_then(|d| air.secret.decrypt(d).ok())
+                                .and_then(|d| postcard::from_bytes::<Sealed>(&d).ok());
+                            let data = match sealed {
+                                Some(sealed) if sealed.payload.0 == air.name => {
+                                    let sender = air.resolver.resolve(sealed.signer, None).await;
+                                    sealed.verify(&sender, &[]).ok().map(|_| (sealed.signer, sealed.payload.1))
+                                },
+                                _ => None
+                            };
+                            tx.send((epoch, cursor, data)).await.unwrap();
                         } else {panic!("Bad Air Server");}
                     }
+                    if !more {break}
                 },
                 response => {panic!("Bad Air Server: {response:?}");}
-            }
-        }});
-        InboxHandler(self, rx)
+            }}
+        }
     }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::names::SystemClock;
 
     #[test]
     fn channel() {
-        let secret = Secret::new();
+        use crate::server::loopback_server;
+        let (server, secret, resolver) = (Secret::new(), Secret::new(), crate::names::Resolver::start());
         let key = secret.harden();
         let name = secret.name();
 
-        let air = crate::Air::new(secret.clone());
+        let air = crate::Air::new(secret.clone(), crate::Config{server: server.name(), resolver: resolver.clone(), ..crate::Config::default()});
+        air.handle.block_on(loopback_server(&server, &resolver, Arc::new(SystemClock)));
 
         let (mut stream, sink) = Channel::new(key).start(air.clone(), secret);
 
@@ -210,13 +684,13 @@ mod test {
             let content = b"hello".to_vec();
             let rid = sink.write(content.clone()).await;
             let (timestamp, data) = stream.read().await;
-            assert_eq!(stream.channel(), &Channel{key, index: 1, timestamp});
+            assert_eq!(stream.channel(), &Channel{server: None, key, index: 1, timestamp, legacy: false, checkpoint_slot: 0});
             assert_eq!(data, Event::Data(name, content.clone(), Some(rid)));
 
             let content2 = b"goodbye".to_vec();
             let rid = sink.write(content2.clone()).await;
             let (timestamp2, data2) = stream.read().await;
-            assert_eq!(stream.channel(), &Channel{key, index: 2, timestamp: timestamp2});
+            assert_eq!(stream.channel(), &Channel{server: None, key, index: 2, timestamp: timestamp2, legacy: false, checkpoint_slot: 0});
             assert_eq!(data2, Event::Data(name, content2.clone(), Some(rid)));
 
             let write = tokio::spawn(async move {
@@ -233,4 +707,250 @@ mod test {
             assert_eq!(data, Event::Data(name, b"late".to_vec(), Some(rid)));
         });
     }
+
+    #[test]
+    fn checkpoint() {
+        use crate::server::loopback_server;
+        let (server, secret, resolver) = (Secret::new(), Secret::new(), crate::names::Resolver::start());
+        let key = secret.harden();
+
+        let air = crate::Air::new(secret.clone(), crate::Config{server: server.name(), resolver: resolver.clone(), ..crate::Config::default()});
+        air.handle.block_on(loopback_server(&server, &resolver, Arc::new(SystemClock)));
+        let (mut stream, sink) = Channel::new(key).start(air.clone(), secret.clone());
+
+        air.handle.block_on(async {
+            for data in [b"one".to_vec(), b"two".to_vec()] {
+                sink.write(data).await;
+                while !matches!(stream.read().await.1, Event::Data(..)) {}
+            }
+            stream.channel().checkpoint(air.clone(), secret.clone(), b"state".to_vec()).await.unwrap();
+
+            let (mut fresh, _) = Channel::new(key).start(air.clone(), secret.clone());
+            let (timestamp, event) = fresh.read().await;
+            assert_eq!(event, Event::Checkpoint(secret.name(), b"state".to_vec(), true));
+            assert_eq!(fresh.channel(), &Channel{server: None, key, index: 2, timestamp, legacy: false, checkpoint_slot: 0});
+            assert_eq!(timestamp, stream.channel().timestamp);
+        });
+    }
+
+    #[test]
+    fn prune() {
+        use crate::server::loopback_server;
+        let (server, secret, resolver) = (Secret::new(), Secret::new(), crate::names::Resolver::start());
+        let key = secret.harden();
+
+        let config = crate::Config{server: server.name(), resolver: resolver.clone(), ..crate::Config::default()};
+        let air = crate::Air::new(secret.clone(), config.clone());
+        let verifying = crate::Air::new(secret.clone(), crate::Config{verify_checkpoints: true, ..config});
+        air.handle.block_on(loopback_server(&server, &resolver, Arc::new(SystemClock)));
+        let (mut stream, sink) = Channel::new(key).start(air.clone(), secret.clone());
+
+        air.handle.block_on(async {
+            for data in [b"one".to_vec(), b"two".to_vec()] {
+                sink.write(data).await;
+                while !matches!(stream.read().await.1, Event::Data(..)) {}
+            }
+            stream.channel().checkpoint(air.clone(), secret.clone(), b"state".to_vec()).await.unwrap();
+            stream.channel().prune(air.clone(), secret.clone()).await.unwrap();
+
+            //Replaying from the start finds the history gone and starts from the checkpoint after all
+            let (mut fresh, _) = Channel::new(key).start(verifying.clone(), secret.clone());
+            let (timestamp, event) = fresh.read().await;
+            assert_eq!(event, Event::Checkpoint(secret.name(), b"state".to_vec(), true));
+            assert_eq!(fresh.channel(), &Channel{server: None, key, index: 2, timestamp, legacy: false, checkpoint_slot: 0});
+
+            sink.write(b"three".to_vec()).await;
+            let data = loop {if let (_, Event::Data(_, data, None)) = fresh.read().await {break data}};
+            assert_eq!(data, b"three".to_vec());
+        });
+    }
+
+    #[test]
+    fn rekey() {
+        use crate::server::loopback_server;
+        let (server, resolver) = (Secret::new(), crate::names::Resolver::start());
+        let secrets = [Secret::new(), Secret::new(), Secret::new()];
+        let key = secrets[0].harden();
+        let config = crate::Config{server: server.name(), resolver: resolver.clone(), ..crate::Config::default()};
+        let airs = secrets.clone().map(|secret| crate::Air::new(secret, config.clone()));
+        airs[0].handle.block_on(loopback_server(&server, &resolver, Arc::new(SystemClock)));
+        let [(mut a, sink), (mut b, _), (mut removed, _)] = [0, 1, 2].map(|i| Channel::new(key).start(airs[i].clone(), secrets[i].clone()));
+
+        airs[0].handle.block_on(async {
+            async fn next(stream: &mut Stream) -> Event {
+                loop {match stream.read().await.1 {Event::Head => {}, event => break event}}
+            }
+            sink.write(b"before".to_vec()).await;
+            for stream in [&mut a, &mut b, &mut removed] {
+                assert!(matches!(next(stream).await, Event::Data(_, data, _) if data == b"before"));
+            }
+
+            let rid = sink.rekey(vec![secrets[1].name()]);
+            assert_eq!(next(&mut a).await, Event::Forward(Some(rid)));
+            assert_eq!(next(&mut b).await, Event::Forward(None));
+            assert_eq!(next(&mut removed).await, Event::Garbage);
+            assert_ne!(a.channel().key, key);
+            assert_eq!(a.channel(), &Channel{server: None, key: b.channel().key, index: 0, timestamp: a.channel().timestamp, legacy: false, checkpoint_slot: 0});
+
+            //Writes go to the new channel without the sink changing
+            sink.write(b"after".to_vec()).await;
+            assert!(matches!(next(&mut a).await, Event::Data(_, data, Some(_)) if data == b"after"));
+            assert!(matches!(next(&mut b).await, Event::Data(_, data, None) if data == b"after"));
+            assert!(tokio::time::timeout(tokio::time::Duration::from_secs(1), next(&mut removed)).await.is_err());
+        });
+    }
+
+    #[test]
+    fn mailbox() {
+        use crate::server::loopback_server;
+        let (server, resolver) = (Secret::new(), crate::names::Resolver::start());
+        let config = crate::Config{server: server.name(), resolver: resolver.clone(), ..crate::Config::default()};
+        let (alice, bob) = (Secret::new(), Secret::new());
+        let alice_air = crate::Air::new(alice.clone(), config.clone());
+        let bob_air = crate::Air::new(bob.clone(), config);
+        bob_air.handle.block_on(loopback_server(&server, &resolver, Arc::new(SystemClock)));
+
+        let mut inbox = Inbox::default().start(bob_air.clone());
+        bob_air.handle.block_on(async {
+            let conn = alice_air.purser.anonymous(server.name()).await.unwrap();
+            let (mailbox, policy) = loop {
+                if let Ok(located) = Inbox::locate(&alice_air, &conn, server.name(), bob.name()).await {break located}
+                sleep(Duration::from_millis(10)).await;
+            };
+            let epoch = bob_air.now() / MAILBOX_EPOCH;
+            assert_eq!(mailbox, Inbox::mailbox(&bob, epoch).public_key());
+            assert_eq!(policy, Policy::of(&bob_air.resolver.resolve(bob.name(), None).await));
+
+            //Announcing again prunes the first, the sender still finds the latest
+            Inbox::announce(&bob_air, server.name(), epoch).await;
+            assert_eq!(Inbox::locate(&alice_air, &conn, server.name(), bob.name()).await.unwrap().0, mailbox);
+
+            InboxHandler::send(alice_air.clone(), bob.name(), b"location".to_vec());
+            assert_eq!(inbox.read().await.1, Some((alice.name(), b"location".to_vec())));
+        });
+    }
+
+    #[test]
+    fn server() {
+        use crate::server::loopback_server;
+        let (servers, resolver) = ([Secret::new(), Secret::new()], crate::names::Resolver::start());
+        let (alice, bob) = (Secret::new(), Secret::new());
+        //Each member is at home on a different server, the channel is where it was created
+        let alice_air = crate::Air::new(alice.clone(), crate::Config{server: servers[0].name(), resolver: resolver.clone(), ..crate::Config::default()});
+        let bob_air = crate::Air::new(bob.clone(), crate::Config{server: servers[1].name(), resolver: resolver.clone(), ..crate::Config::default()});
+        for server in &servers {alice_air.handle.block_on(loopback_server(server, &resolver, Arc::new(SystemClock)));}
+
+        let key = SecretKey::new();
+        let (_, sink) = Channel::at(key, servers[0].name()).start(alice_air.clone(), alice.clone());
+        let (mut stream, _) = Channel::at(key, servers[0].name()).start(bob_air.clone(), bob);
+        bob_air.handle.block_on(async {
+            sink.write(b"hello".to_vec()).await;
+            loop {match stream.read().await.1 {
+                Event::Data(signer, data, None) => {assert_eq!((signer, data), (alice.name(), b"hello".to_vec())); break},
+                Event::Head => {},
+                event => panic!("Unexpected Event {event:?}")
+            }}
+        });
+    }
+
+    #[test]
+    fn legacy() {
+        use crate::server::loopback_server;
+        let (server, secret, resolver) = (Secret::new(), Secret::new(), crate::names::Resolver::start());
+        let air = crate::Air::new(secret.clone(), crate::Config{server: server.name(), resolver: resolver.clone(), ..crate::Config::default()});
+        let key = SecretKey::new();
+        air.handle.block_on(async {
+            loopback_server(&server, &resolver, Arc::new(SystemClock)).await;
+
+            //Written the way channels were before logs, a slot for each index
+            let connection = air.purser.connect(server.name()).await.unwrap();
+            let (log, ..) = Channel::log(&air, server.name(), &key);
+            let signer = secret.derive(&[Id::hash(CHANNEL)]);
+            for (index, data) in [b"one".to_vec(), b"two".to_vec()].into_iter().enumerate() {
+                let slot = log.derive(&[Id::hash(&(index as u64))]);
+                let signed = postcard::to_allocvec(&Signed::new(&signer, data)).unwrap();
+                let encrypted = postcard::to_allocvec(&slot.public_key().encrypt_with(signed, &air.config.padding, None)).unwrap();
+                let response = connection.send(Request::Create(KeySigned::new(&slot, encrypted))).await.recv().await;
+                assert!(matches!(response, Response::Create(..)));
+            }
+        });
+
+        let (mut stream, sink) = Channel{legacy: true, ..Channel::at(key, server.name())}.start(air.clone(), secret.clone());
+        air.handle.block_on(async {
+            for data in [b"one", b"two"] {
+                assert_eq!(stream.read().await.1, Event::Data(secret.name(), data.to_vec(), None));
+            }
+            //New writes go to the log once the slots run out
+            let rid = sink.write(b"three".to_vec()).await;
+            let event = loop {match stream.read().await.1 {Event::Head => {}, event => break event}};
+            assert_eq!(event, Event::Data(secret.name(), b"three".to_vec(), Some(rid)));
+            assert_eq!((stream.channel().index, stream.channel().legacy), (1, false));
+        });
+    }
+
+    #[test]
+    fn catch_up() {
+        use crate::server::loopback_server;
+        let (server, secret, resolver) = (Secret::new(), Secret::new(), crate::names::Resolver::start());
+        let air = crate::Air::new(secret.clone(), crate::Config{server: server.name(), resolver: resolver.clone(), ..crate::Config::default()});
+        air.handle.block_on(loopback_server(&server, &resolver, Arc::new(SystemClock)));
+
+        let key = SecretKey::new();
+        let count = 2 * PIPELINE + 5;
+        let (mut written, sink) = Channel::at(key, server.name()).start(air.clone(), secret.clone());
+        air.handle.block_on(async {
+            for i in 0..count {sink.write(i.to_le_bytes().to_vec()).await;}
+            while written.channel().index < count {written.read().await;}
+        });
+
+        //Joins far enough behind to fill the pipeline more than once, writing before it has read anything
+        let name = secret.name();
+        let (mut stream, sink) = Channel::at(key, server.name()).start(air.clone(), secret);
+        air.handle.block_on(async {
+            let rid = sink.write(b"ours".to_vec()).await;
+            let mut events = vec![];
+            while events.len() as u64 <= count {
+                match stream.read().await.1 {Event::Head => {}, event => events.push(event)}
+            }
+            let expected = (0..count).map(|i| Event::Data(name, i.to_le_bytes().to_vec(), None));
+            assert_eq!(events, expected.chain([Event::Data(name, b"ours".to_vec(), Some(rid))]).collect::<Vec<_>>());
+            assert_eq!(stream.channel().index, count + 1);
+        });
+    }
+
+    #[test]
+    fn faults() {
+        use crate::server::{loopback_server, simulator::{Network, Faults}};
+        let (server, resolver) = (Secret::new(), crate::names::Resolver::start());
+        let name = server.name().to_string();
+        let config = crate::Config{server: server.name(), resolver: resolver.clone(), ..crate::Config::default()};
+        let (alice, bob) = (Secret::new(), Secret::new());
+        let alice_air = crate::Air::new(alice.clone(), config.clone());
+        let bob_air = crate::Air::new(bob.clone(), config);
+        let network = Network::new(11, Faults{drop: 0.1, duplicate: 0.1, reorder: 0.1, delay: 20});
+        alice_air.handle.block_on(async {
+            loopback_server(&server, &resolver, Arc::new(SystemClock)).await;
+            alice_air.resolver.publish(&server, crate::names::URL, network.route(&name)).await;
+        });
+
+        let key = SecretKey::new();
+        let (mut ours, sink) = Channel::at(key, server.name()).start(alice_air.clone(), alice);
+        let (mut theirs, _) = Channel::at(key, server.name()).start(bob_air.clone(), bob);
+        bob_air.handle.block_on(async {
+            async fn data(stream: &mut Stream) -> Vec<u8> {
+                loop {if let (_, Event::Data(_, data, _)) = stream.read().await {break data}}
+            }
+            for i in 0..6u8 {
+                //Written while the server is unreachable, it goes through once the partition heals
+                if i == 3 {network.partition(&name);}
+                sink.write(vec![i]).await;
+                if i == 3 {
+                    sleep(Duration::from_secs(2)).await;
+                    network.heal(&name);
+                }
+                assert_eq!(data(&mut ours).await, vec![i]);
+                assert_eq!(data(&mut theirs).await, vec![i]);
+            }
+        });
+    }
 }
@@ -1,12 +1,12 @@
-use crate::names::{Id, Secret, Name, secp256k1::SecretKey, now};
+use crate::names::{Id, Secret, Name, secp256k1::SecretKey};
 
-use crate::channel::{Inbox, InboxHandler, Sink, Stream, Channel, Event};
+use crate::channel::{Inbox, InboxHandler, Sink, Stream, Channel, LegacyChannel, Event};
 use crate::cache::Cache;
 use crate::Air;
 
-use std::collections::{HashSet, HashMap, BTreeMap, VecDeque, btree_map::Entry};
+use std::collections::{HashSet, HashMap, BTreeMap, BTreeSet, VecDeque, btree_map::Entry};
 use std::marker::PhantomData;
-use std::hash::Hash;
+use std::hash::{Hash, Hasher};
 use std::any::TypeId;
 use std::sync::Arc;
 use std::any::Any;
@@ -17,6 +17,7 @@ use serde::{Serialize, Deserialize};
 
 use futures_util::{stream::FuturesUnordered, StreamExt};
 use tokio::task::JoinSet;
+use tokio::sync::Notify;
 
 use crate::ams::{Ams, Ref};
 
@@ -67,12 +68,12 @@ impl<C: Contract> Reactants<C> {
 }
 
 #[derive(Clone)]
-pub struct AnyInstance(Arc<Box<dyn Fn() -> Box<dyn Any + Send + Sync> + Send + Sync>>, Location);
+pub struct AnyInstance(Arc<Box<dyn Fn() -> Box<dyn Any + Send + Sync> + Send + Sync>>, Shared);
 impl Debug for AnyInstance {fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {f.debug_tuple("AnyInstance").field(&self.1).finish()}}
 impl AnyInstance {
     pub fn new<C: Contract>(instance: Instance<C>) -> Self {
-        let location = instance.location;
-        AnyInstance(Arc::new(Box::new(move || Box::new(instance.clone()))), location)
+        let shared = Shared(instance.location, *instance.key.clone().load());
+        AnyInstance(Arc::new(Box::new(move || Box::new(instance.clone()))), shared)
     }
     pub fn downcast<C: Contract>(&self) -> Option<Instance<C>> {
         (self.0)().downcast::<Instance<C>>().ok().map(|i| *i)
@@ -152,7 +153,12 @@ pub struct Instance<C: Contract>{
     confirmed: Ams<Option<C>, AnyOutput<C>>,
     pending_queue: Arc<Mutex<VecDeque<PendingReactant<C>>>>,
     pending: Ams<Option<C>, ()>,
-    head: Ams<bool, bool>
+    head: Ams<bool, bool>,
+    ///Members whose checkpoint disagreed with the history, only checked with Config::verify_checkpoints
+    mismatched: Ams<BTreeSet<Name>, Name>,
+    checkpoint: Arc<Notify>,
+    ///The channels current key, the locations until someone re-keys it. Updated with the id of our re-key
+    key: Ams<SecretKey, Option<Id>>,
 }
 
 impl<C: Contract> std::fmt::Debug for Instance<C> {fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
@@ -162,28 +168,55 @@ impl<C: Contract> std::fmt::Debug for Instance<C> {fn fmt(&self, f: &mut std::fm
 impl<C: Contract> Instance<C> {
     pub fn id(&self) -> Id {self.id}
 
-    fn start(air: Air, location: Location, init: Option<C::Init>) -> Self {
+    ///Our clock corrected to the server the contract is kept on, which timestamps its reactants
+    pub fn now(&self) -> u64 {self.air.now_for(&self.location.server)}
+
+    ///Key is the one the channel is on now, which is not the locations own once it was re-keyed
+    fn start(air: Air, location: Location, key: SecretKey, init: Option<C::Init>) -> Self {
         let id = Id::hash(&location);
         let cache = Cache::new(format!("{}/{}/{}", air.name, C::id(), id)).unwrap();
         let secret = air.secret.derive(&[C::id(), id]);
-        let (channel, contract) = cache.get::<(Channel, Option<C>)>("instance").unwrap().unwrap_or((Channel::new(location.key), None));
-        let (stream, sink) = channel.start(air.clone(), secret);
+        let legacy = |(channel, contract): (LegacyChannel, Option<C>)| (Channel{server: Some(location.server), ..channel.into()}, contract);
+        let (channel, contract) = cache.get("instance", legacy).unwrap().unwrap_or((Channel::at(key, location.server), None));
+        let (stream, sink) = channel.start(air.clone(), secret.clone());
         if let Some(init) = init.as_ref() && contract.is_none() {
             sink.write_sync(postcard::to_allocvec(&(id, postcard::to_allocvec(init).unwrap())).unwrap());
         }
-        let contract = contract.or(init.map(|i| C::init(i, Metadata::pending(air.name))));
+        let contract = contract.or(init.map(|i| C::init(i, Metadata::pending(air.name, air.now_for(&location.server)))));
         let reactants = Arc::new(C::reactants());
         let confirmed = Ams::new(contract.clone());
         let pending_queue = Arc::new(Mutex::new(VecDeque::new()));
         let pending = Ams::new(contract);
         let head = Ams::new(false);
-        let instance = Instance{sink, air: air.clone(), id, location, reactants, confirmed, pending_queue, pending, head};
-        air.handle.spawn(instance.clone().run(cache, stream));
+        let key = Ams::new(channel.key);
+        let mismatched = Ams::new(BTreeSet::new());
+        let instance = Instance{sink, air: air.clone(), id, location, reactants, confirmed, pending_queue, pending, head, mismatched, checkpoint: Arc::default(), key};
+        air.handle.spawn(instance.clone().run(cache, stream, secret));
         instance
     }
 
+    ///Publishes the confirmed state so members joining later start from here instead of replaying the history
+    pub fn checkpoint(&self) {self.checkpoint.notify_one();}
+
+    ///Lets the server drop the history before the latest checkpoint
+    pub fn prune(&self) {
+        Channel::at(*self.key.clone().load(), self.location.server).prune(self.air.clone(), self.air.secret.derive(&[C::id(), self.id]));
+    }
+
+    ///Moves the contract to a fresh channel only the listed members are told about, everyone else who was
+    ///shared it stops seeing new reactants. Members are not tracked so list everyone that should remain.
+    ///Resolves once we are on the new channel and the state it was forwarded with is published
+    pub fn rekey(&self, members: Vec<Name>) -> impl Future<Output = ()> + use<C> {
+        let mut key = self.key.clone();
+        let rid = self.sink.rekey(members);
+        async move {while key.listen().await != Some(rid) {}}
+    }
+
+    ///Sends the location, which the contract keeps its id and signing path by, along with the key its
+    ///channel is on now
     pub fn share(&self, name: Name) {
-        InboxHandler::send(self.air.clone(), name, postcard::to_allocvec(&self.location).unwrap());
+        let shared = Shared(self.location, *self.key.clone().load());
+        InboxHandler::send(self.air.clone(), name, postcard::to_allocvec(&shared).unwrap());
     }
 
     pub fn confirmed_update(&mut self) -> Option<AnyOutput<C>> {self.confirmed.get_update()}
@@ -207,9 +240,9 @@ impl<C: Contract> Instance<C> {
     ///If the outer result is Err the reactant has not been sent and will not update its state in the future
     pub fn try_apply<O: Send + Sync + Clone + Debug, E: Sync + Send + Clone + Debug, R: Reactant<C, Output = Result<O, E>>>(&mut self, reactant: R) -> PendingResult<C, O, E, R> {
         let id = self.reactants.id::<R>().expect("Reactant is not listed in Contract::reactants()");
+        let metadata = Metadata::pending(self.air.name, self.now());
         let mut pending = self.pending.lock();
         let mut queue = self.pending_queue.lock().unwrap();
-        let metadata = Metadata::pending(self.air.name);
         match reactant.clone().apply(pending.as_mut().unwrap(), metadata) {
             Err(e) => PendingResult::Err(e),
             Ok(output) => {
@@ -225,9 +258,9 @@ impl<C: Contract> Instance<C> {
 
     pub fn apply<R: Reactant<C>>(&mut self, reactant: R) -> Pending<C, R> {
         let id = self.reactants.id::<R>().expect("Reactant is not listed in Contract::reactants()");
+        let metadata = Metadata::pending(self.air.name, self.now());
         let mut pending = self.pending.lock();
         let mut queue = self.pending_queue.lock().unwrap();
-        let metadata = Metadata::pending(self.air.name);
         let output = Pending::new(reactant.clone().apply(pending.as_mut().unwrap(), metadata));
         let id = self.sink.write_sync(postcard::to_allocvec(&(id, postcard::to_allocvec(&reactant).unwrap())).unwrap());
         let reactant = PendingReactant::new(id, reactant, output.clone());
@@ -236,9 +269,18 @@ impl<C: Contract> Instance<C> {
         output
     }
 
-    async fn run(mut self, mut cache: Cache, mut stream: Stream) {
+    async fn run(mut self, mut cache: Cache, mut stream: Stream, secret: Secret) {
         loop {
-            let (timestamp, event) = stream.read().await;
+            let (timestamp, event) = tokio::select! {
+                read = stream.read() => read,
+                //The channels position always matches the confirmed state between reads
+                _ = self.checkpoint.notified() => {
+                    if let Some(contract) = &*self.confirmed.load() {
+                        stream.channel().checkpoint(self.air.clone(), secret.clone(), postcard::to_allocvec(contract).unwrap());
+                    }
+                    continue;
+                }
+            };
             match event {
                 Event::Head => {
                     let mut lock = self.head.lock();
@@ -259,6 +301,7 @@ impl<C: Contract> Instance<C> {
                         } else if id == self.id {
                             println!("Found Contract Init Again(ignoring)");
                         } else {
+                            let now = self.now();
                             let mut pending = self.pending.lock();
                             let mut queue = self.pending_queue.lock().unwrap();
                             let mut confirmed = self.confirmed.lock();
@@ -272,7 +315,7 @@ impl<C: Contract> Instance<C> {
                             if let Some(output) = output {
                                 *pending = confirmed.clone();
                                 for reactant in &mut *queue {
-                                    reactant.apply(pending.as_mut().unwrap(), Metadata::pending(self.air.name));
+                                    reactant.apply(pending.as_mut().unwrap(), Metadata::pending(self.air.name, now));
                                 }
                                 if queue.is_empty() {pending.commit_silent();} else {pending.commit(());}
                                 confirmed.commit(output);
@@ -280,6 +323,46 @@ impl<C: Contract> Instance<C> {
                         }
                     }
                 },
+                //One met while replaying is only checked against the history, with nothing to check it against
+                //it is passed over rather than taken on trust
+                Event::Checkpoint(signer, state, false) => match &*self.confirmed.load() {
+                    Some(contract) if postcard::to_allocvec(contract).unwrap() != state => {
+                        let mut mismatched = self.mismatched.lock();
+                        mismatched.insert(signer);
+                        mismatched.commit(signer);
+                    },
+                    Some(_) => {},
+                    None => println!("Unverifiable Checkpoint From {signer}(ignoring)")
+                },
+                Event::Checkpoint(signer, state, true) => {
+                    match postcard::from_bytes::<C>(&state) {
+                        Ok(contract) => {
+                            let now = self.now();
+                            let mut pending = self.pending.lock();
+                            let mut queue = self.pending_queue.lock().unwrap();
+                            let mut confirmed = self.confirmed.lock();
+                            *confirmed = Some(contract);
+                            *pending = confirmed.clone();
+                            for reactant in &mut *queue {
+                                reactant.apply(pending.as_mut().unwrap(), Metadata::pending(self.air.name, now));
+                            }
+                            pending.commit(());
+                            confirmed.commit_silent();
+                        },
+                        Err(e) => println!("Invalid Checkpoint From {signer}: {e:?}")
+                    }
+                },
+                //Whoever is shared the new channel starts from the state it was forwarded with, which the member
+                //that re-keyed publishes once so the rest don't each checkpoint the same state. It is in place
+                //before the new key is shared so nobody starts on the channel without it
+                Event::Forward(rid) => {
+                    if rid.is_some() && let Some(contract) = &*self.confirmed.load() {
+                        let _ = stream.channel().checkpoint(self.air.clone(), secret.clone(), postcard::to_allocvec(contract).unwrap()).await;
+                    }
+                    let mut key = self.key.lock();
+                    *key = stream.channel().key;
+                    key.commit(rid);
+                },
                 Event::Garbage => {}
             }
             cache.insert("instance", &(&stream.channel(), &*self.confirmed.load())).unwrap();
@@ -287,12 +370,18 @@ impl<C: Contract> Instance<C> {
     }
 
     pub fn is_near_head(&mut self) -> bool {*self.head.load()}
+
+    ///Members whose checkpoint did not match the history, so neither they nor anyone starting from their
+    ///checkpoint can be trusted to agree on the state
+    pub fn mismatched(&mut self) -> Ref<BTreeSet<Name>> {self.mismatched.load()}
+    ///Waits for the next member whose checkpoint does not match the history
+    pub async fn listen_mismatched(&mut self) -> Name {self.mismatched.listen().await}
     pub async fn head(&mut self) {
         loop { if *self.head.load() {break;} self.head.listen().await;}
     }
 }
 
-type Builder = Arc<Box<dyn Fn(Location) -> AnyInstance + Send + Sync>>;
+type Builder = Arc<Box<dyn Fn(Shared) -> AnyInstance + Send + Sync>>;
 
 #[derive(Clone)]
 pub struct Contracts(Ams<BTreeMap<Id, BTreeMap<Id, AnyInstance>>, AnyInstance>, Ams<BTreeMap<Id, Builder>, Id>, Air);
@@ -304,7 +393,7 @@ impl Contracts {
         if !builders.load().contains_key(&c_id) {
             let mut builders = builders.lock();
             if let Entry::Vacant(vac) = builders.entry(c_id) {
-                vac.insert(Arc::new(Box::new(move |location: Location| AnyInstance::new(Instance::<C>::start(air.clone(), location, None)))));
+                vac.insert(Arc::new(Box::new(move |Shared(location, key)| AnyInstance::new(Instance::<C>::start(air.clone(), location, key, None)))));
                 builders.commit(c_id);
             }
         }
@@ -313,7 +402,7 @@ impl Contracts {
     pub fn create<C: Contract>(&self, init: C::Init) -> Instance<C> {
         self.register::<C>();
         let c_id = C::id();
-        let location = Location::new::<C>(&self.2.secret, &init);
+        let location = Location::new::<C>(&self.2.secret, self.2.config.server, &init);
         let id = Id::hash(&location);
         let mut instances = self.0.clone();
         match instances.load().get(&c_id).and_then(|i| i.get(&id)) {
@@ -323,7 +412,7 @@ impl Contracts {
                 match instances.entry(c_id).or_default().entry(id) {
                     Entry::Occupied(occ) => occ.get().downcast().unwrap(),
                     Entry::Vacant(vac) => {
-                        let instance = Instance::<C>::start(self.2.clone(), location, Some(init));
+                        let instance = Instance::<C>::start(self.2.clone(), location, location.key, Some(init));
                         let any = AnyInstance::new(instance.clone());
                         vac.insert(any.clone());
                         instances.commit(any);
@@ -334,7 +423,8 @@ impl Contracts {
         }
     }
 
-    fn build(&self, location: Location) -> Option<AnyInstance> {
+    fn build(&self, shared: Shared) -> Option<AnyInstance> {
+        let Shared(location, _) = shared;
         let id = Id::hash(&location);
         let mut instances = self.0.clone();
         match instances.load().get(&location.contract_id).and_then(|i| i.get(&id)) {
@@ -344,7 +434,7 @@ impl Contracts {
                 match instances.entry(location.contract_id).or_default().entry(id) {
                     Entry::Occupied(occ) => Some(occ.get().clone()),
                     Entry::Vacant(vac) => {
-                        let instance = (self.1.clone().load().get(&location.contract_id)?)(location);
+                        let instance = (self.1.clone().load().get(&location.contract_id)?)(shared);
                         vac.insert(instance.clone());
                         instances.commit(instance.clone());
                         Some(instance) 
@@ -438,9 +528,16 @@ pub struct Manager {
 impl Manager {
     pub fn start(air: Air) -> Contracts {
         let cache = Cache::new(format!("./{}/{}.db", air.name, air.name)).unwrap();
-        let root = cache.get::<Root>("root").unwrap().unwrap_or_default();
+        //The inbox position from before mailboxes rotated means nothing to the new ones
+        let legacy = |(_, contracts): (u64, BTreeMap<Id, (LegacyChannel, Vec<LegacyLocation>)>)| Root{
+            inbox: Inbox::default(),
+            contracts: contracts.into_iter().map(|(id, (channel, locations))| {
+                (id, (channel.into(), locations.into_iter().map(|l| l.on(air.config.server)).collect()))
+            }).collect()
+        };
+        let root = cache.get("root", legacy).unwrap().unwrap_or_default();
 
-        let inbox = root.inbox.start(air.clone());
+        let inbox = root.inbox.clone().start(air.clone());
         let contracts = Contracts(Ams::new(BTreeMap::new()), Ams::new(BTreeMap::new()), air.clone());
         let i = contracts.clone();
 
@@ -470,11 +567,14 @@ impl Manager {
         }).1
     }
 
-    async fn store(&mut self, location: Location, write: bool) {
+    ///Other devices start from the stored key, the location alone would leave them on the channel as it
+    ///was before any re-key
+    async fn store(&mut self, shared: Shared, write: bool) {
+        let Shared(location, _) = shared;
         let locations = self.register(location.contract_id);
         if locations.insert(location) {
             let sink = self.sinks.get(&location.contract_id).unwrap();
-            if write {sink.write(postcard::to_allocvec(&location).unwrap()).await;}
+            if write {sink.write(postcard::to_allocvec(&shared).unwrap()).await;}
         }
     }
 
@@ -483,25 +583,27 @@ impl Manager {
             tokio::select!{ biased;
                 c_id = self.contracts.1.listen() => {
                     for location in self.register(c_id).iter().copied().collect::<Vec<_>>() { 
-                        self.contracts.build(location).expect("False Register");
+                        self.contracts.build(Shared(location, location.key)).expect("False Register");
                     }
                 },
                 instance = self.contracts.0.listen() => {self.store(instance.1, true).await},
                 (_, location) = self.inbox.read() => {
-                    self.root.inbox = *self.inbox.inbox();
-                    if let Some(location) = location.and_then(|l| postcard::from_bytes(&l).ok()) {
-                        self.store(location, true).await;
-                        self.contracts.build(location);
+                    self.root.inbox = self.inbox.inbox().clone();
+                    if let Some(shared) = location.and_then(|(_, l)| Shared::decode(&l, self.contracts.2.config.server)) {
+                        self.store(shared, true).await;
+                        self.contracts.build(shared);
                     }
+                    self.cache.insert("root", &self.root).unwrap();
+                    self.inbox.ack();
                 },
                 Some(Ok((id, mut stream, _, event))) = self.joinset.join_next() => {
                     self.root.contracts.get_mut(&id).unwrap().0 = *stream.channel();
 
                     if let Event::Data(_, data, _) = event 
-                    && let Ok((contract_hash, key)) = postcard::from_bytes::<(Id, SecretKey)>(&data) {
-                        let location = Location{key, contract_id: id, contract_hash};
-                        self.store(location, false).await;
-                        self.contracts.build(location);
+                    && let Some(shared) = Shared::decode(&data, self.contracts.2.config.server)
+                    && shared.0.contract_id == id {
+                        self.store(shared, false).await;
+                        self.contracts.build(shared);
                     }
 
                     self.joinset.spawn(async move {
@@ -523,23 +625,53 @@ pub struct Metadata {
     pub confirmed: bool
 }
 impl Metadata {
-    fn pending(signer: Name) -> Self {Metadata{signer, timestamp: now(), confirmed: false}}
+    fn pending(signer: Name, timestamp: u64) -> Self {Metadata{signer, timestamp, confirmed: false}}
     fn confirmed(signer: Name, timestamp: u64) -> Self {Metadata{signer, timestamp, confirmed: true}}
 }
 
-#[derive(Serialize, Deserialize, Hash, Debug, Clone, Copy, Eq, PartialEq)]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Eq, PartialEq)]
 pub(crate) struct Location {
     pub key: SecretKey,
-    //pub servers: Vec<Name>,
+    ///The creators home server when it was created, the channel stays there for every member
+    pub server: Name,
     pub contract_id: Id,
     pub contract_hash: Id
 }
 impl Location {
-    pub fn new<C: Contract>(secret: &Secret, init: &C::Init) -> Self {
+    pub fn new<C: Contract>(secret: &Secret, server: Name, init: &C::Init) -> Self {
         let c_id = C::id();
         let hash = Id::hash(&init);
         let key = secret.derive(&[c_id, hash]).harden();
-        Location{key, contract_id: c_id, contract_hash: hash}
+        Location{key, server, contract_id: c_id, contract_hash: hash}
+    }
+
+    ///Reads a location from this release or one shared and stored before locations named their server
+    fn decode(bytes: &[u8], server: Name) -> Option<Self> {
+        postcard::from_bytes(bytes).ok().or_else(|| postcard::from_bytes::<LegacyLocation>(bytes).ok().map(|l| l.on(server)))
+    }
+}
+//The server is left out so contracts keep the id, and the path members sign under, they had before
+//locations named it
+impl Hash for Location {
+    fn hash<H: Hasher>(&self, state: &mut H) {(self.key, self.contract_id, self.contract_hash).hash(state)}
+}
+
+///A location as it is shared and stored, with the key its channel is on now
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub(crate) struct Shared(Location, SecretKey);
+impl Shared {
+    ///Also reads a bare location, shared or stored before the key was sent along with it
+    fn decode(bytes: &[u8], server: Name) -> Option<Self> {
+        postcard::from_bytes(bytes).ok().or_else(|| Location::decode(bytes, server).map(|l| Shared(l, l.key)))
+    }
+}
+
+///How a Location was kept before it named its server, every contract was on the home server
+#[derive(Deserialize)]
+struct LegacyLocation {key: SecretKey, contract_id: Id, contract_hash: Id}
+impl LegacyLocation {
+    fn on(self, server: Name) -> Location {
+        Location{key: self.key, server, contract_id: self.contract_id, contract_hash: self.contract_hash}
     }
 }
 
@@ -548,3 +680,62 @@ struct Root {
     inbox: Inbox,
     contracts: BTreeMap<Id, (Channel, HashSet<Location>)>,
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{Services, names::{Resolver, SystemClock}, server::loopback_server};
+    use tokio::time::{sleep, Duration};
+
+    #[derive(Serialize, Deserialize, Clone, Debug, Default)]
+    struct Log(Vec<String>);
+    impl Contract for Log {
+        type Init = Id;
+        fn id() -> Id {Id::hash("Log")}
+        fn init(_init: Self::Init, _metadata: Metadata) -> Self {Log::default()}
+        fn reactants() -> Reactants<Self> {Reactants::default().add::<Push>()}
+    }
+
+    #[derive(Serialize, Deserialize, Clone, Debug)]
+    struct Push(String);
+    impl Reactant<Log> for Push {
+        fn id() -> Id {Id::hash("Push")}
+        type Output = ();
+        fn apply(self, log: &mut Log, _metadata: Metadata) -> Self::Output {log.0.push(self.0)}
+    }
+
+    #[test]
+    fn share() {
+        let (server, resolver) = (Secret::new(), Resolver::start());
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(loopback_server(&server, &resolver, Arc::new(SystemClock)));
+
+        let (alice, bob) = (Secret::new(), Secret::new());
+        let config = crate::Config{server: server.name(), resolver, ..crate::Config::default()};
+        let (alice_air, ours) = Air::start_with(alice, Services::default(), config.clone());
+        let (_bob_air, theirs) = Air::start_with(bob.clone(), Services::default(), config);
+        alice_air.handle.block_on(async {
+            let mut log = ours.create::<Log>(Id::random());
+            log.apply(Push("before".to_string())).confirmed().await;
+
+            //Shared only once the contract is on a fresh channel, it keeps the id and path it was created with
+            log.rekey(vec![]).await;
+            let conn = alice_air.purser.anonymous(server.name()).await.unwrap();
+            while Inbox::locate(&alice_air, &conn, server.name(), bob.name()).await.is_err() {sleep(Duration::from_millis(10)).await;}
+            let mut instances = theirs.instances::<Log>();
+            log.share(bob.name());
+            let (shared, _) = instances.listen().await;
+            assert_eq!(shared.id(), log.id());
+            shared.head().await;
+            assert_eq!(shared.load_confirmed().0, vec!["before".to_string()]);
+
+            //Each reads the others writes on the new channel
+            shared.apply(Push("bob".to_string())).confirmed().await;
+            while log.load_confirmed().0.len() < 2 {log.listen_confirmed().await;}
+            log.apply(Push("alice".to_string())).confirmed().await;
+            while shared.load_confirmed().0.len() < 3 {shared.listen_confirmed().await;}
+            assert_eq!(shared.load_confirmed().0, ["before", "bob", "alice"].map(str::to_string));
+            assert_eq!(log.load_confirmed().0, ["before", "bob", "alice"].map(str::to_string));
+        });
+    }
+}
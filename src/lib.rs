@@ -3,13 +3,19 @@ mod ams;
 pub use ams::Ref;
 
 pub mod names;
-pub use names::{Secret, Name, Id};
-use names::Resolver;
+pub use names::{Secret, Name, Id, Padding, Clock, SystemClock, ManualClock};
+pub use names::Resolver;
+
+mod stamp;
+pub use stamp::Stamp;
 
 mod storage;
+pub use storage::{StorageBackend, Sqlite, Memory, Sharded};
 
 mod server;
 use server::Purser;
+pub use server::{ChandlerConfig, Limits, Tls};
+pub use rustls::RootCertStore;
 
 mod channel;
 
@@ -26,12 +32,40 @@ use tokio_util::sync::CancellationToken;
 pub struct Context(contract::Contracts, Air);
 impl Context {
     pub fn me(&self) -> Name {self.1.name}
+    pub fn now(&self) -> u64 {self.1.now()}
+    pub fn offset(&self) -> Option<i64> {self.1.offset()}
     pub fn service_secret<S: Service>(&self) -> Secret {self.1.service_secret::<S>()}
     pub fn create<C: Contract>(&self, init: C::Init) -> Instance<C> {self.0.create(init)}
     pub fn list<C: Contract>(&self) -> std::collections::HashMap<Id, Instance<C>> {self.0.list()}
     pub fn instances<C: Contract>(&self) -> Instances<C> {Instances::new(self.0.clone())}
 }
     
+///Client settings, shared by every service started on the Air
+#[derive(Clone, Debug)]
+pub struct Config {
+    ///Buckets channel slot payloads and inbox envelopes are padded to so their size does not reveal what they hold
+    pub padding: Padding,
+    ///Combine ML-KEM with ECDH when encrypting channel slots, they are stored for a long time, and refuse
+    ///server sessions that do not
+    pub hybrid: bool,
+    ///Trusted by wss:// connections, the webpki roots by default
+    pub roots: RootCertStore,
+    ///A SOCKS5 proxy, such as Tor, that every channel and inbox connection goes through so servers never see our IP
+    pub proxy: Option<std::net::SocketAddr>,
+    ///Home server, published on our identity, where our channels are kept and our inbox is served
+    pub server: Name,
+    ///Timestamps pending reactants and lock expiry once corrected to the home server, a ManualClock lets tests skip ahead
+    pub clock: std::sync::Arc<dyn Clock>,
+    ///Replay new contracts from the start and check each members checkpoint against the history instead of
+    ///starting from the latest one, Instance::mismatched lists the members whose checkpoint disagreed
+    pub verify_checkpoints: bool,
+    ///Looks up identities and publishes ours, Airs only see the entries published through the same resolver
+    pub resolver: Resolver,
+}
+impl Default for Config {fn default() -> Self {
+    Config{padding: Padding::default(), hybrid: true, roots: RootCertStore{roots: webpki_roots::TLS_SERVER_ROOTS.to_vec()}, proxy: None, server: Name::orange_me(), clock: std::sync::Arc::new(SystemClock), verify_checkpoints: false, resolver: Resolver::start()}
+}}
+
 #[derive(Clone, Debug)]
 pub struct Air{
     handle: tokio::runtime::Handle,
@@ -40,18 +74,29 @@ pub struct Air{
     secret: Secret,
     name: Name,
     purser: Purser,
-    resolver: Resolver
+    resolver: Resolver,
+    config: Config,
 }
 impl Air {
     pub fn me(&self) -> Name {self.name}
 
+    ///Our clock corrected to the home servers, which timestamps everything it confirms
+    pub fn now(&self) -> u64 {self.now_for(&self.config.server)}
+
+    ///Our clock corrected to the server, contracts kept on another server are timestamped by its clock
+    pub fn now_for(&self, server: &Name) -> u64 {self.purser.now(server)}
+
+    ///Nano seconds the home servers clock is ahead of ours, None until it has answered a request
+    pub fn offset(&self) -> Option<i64> {self.purser.offset(&self.config.server)}
+
     pub fn service_secret<S: Service>(&self) -> Secret {self.secret.derive(&[S::id()])}
 
-    fn new(secret: Secret) -> Self {
+    fn new(secret: Secret, config: Config) -> Self {
         let runtime = tokio::runtime::Builder::new_multi_thread().enable_time().enable_io().build().unwrap();
         let _guard = runtime.enter();
-        let resolver = names::Resolver::start();
-        let purser = server::Purser::start(resolver.clone());
+        let resolver = config.resolver.clone();
+        let purser = server::Purser::start(resolver.clone(), &config.roots, config.proxy, config.clock.clone(), config.hybrid);
+        runtime.block_on(resolver.publish(&secret, names::SERVERS, config.server.to_string()));
 
         let token = CancellationToken::new();
         let tasks = TaskTracker::new();
@@ -62,7 +107,8 @@ impl Air {
             name: secret.name(),
             secret,
             purser,
-            resolver
+            resolver,
+            config
         };
         std::thread::spawn(move || runtime.block_on(async move {
             token.cancelled().await;
@@ -76,16 +122,20 @@ impl Air {
     }
 
     pub fn start(secret: Secret, services: Services) -> (Self, Context) {
-        let air = Self::new(secret.clone());
+        Self::start_with(secret, services, Config::default())
+    }
+
+    pub fn start_with(secret: Secret, services: Services, config: Config) -> (Self, Context) {
+        let air = Self::new(secret.clone(), config);
         let instances = air.handle.clone().block_on(async {contract::Manager::start(air.clone())});
         let context = Context(instances, air.clone());
         services.start(context.clone());
         (air, context)
     }
 
-    pub fn start_server(secret: Secret) {
-        let air = Self::new(secret.clone());
-        air.handle.block_on(server::Chandler::start(secret))
+    pub fn start_server<B: StorageBackend>(secret: Secret, backend: B, config: ChandlerConfig) {
+        let air = Self::new(secret.clone(), Config{resolver: config.resolver.clone(), ..Config::default()});
+        air.handle.block_on(server::Chandler::start(secret, backend, config))
     }
 
     pub fn shutdown(self) {
@@ -2,5 +2,5 @@ const ORANGE_ME_SECRET: &str = "{\"name\":\"03273e58dff6f2e5334c526b0dd0100d20e1
 
 fn main() {
     let secret = serde_json::from_str(ORANGE_ME_SECRET).unwrap();
-    air::Air::start_server(secret)
+    air::Air::start_server(secret, air::Sqlite::open("STORAGE.db"), air::ChandlerConfig::default())
 }
@@ -6,6 +6,8 @@ use serde::ser::Serializer;
 use serde::de::Deserializer;
 
 use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::str::FromStr;
 use std::hash::Hash;
 use std::fmt::Debug;
@@ -13,7 +15,7 @@ use std::fmt::Debug;
 mod fschacha20poly1305;
 pub mod secp256k1;
 
-pub use secp256k1::{Sink, Drain, Message};
+pub use secp256k1::{Sink, Drain, Message, Reply, Resumption, Ticket, Resume};
 
 const TAG: &str = "AIR_NAMES";
 const ORANGEME_NAME: &str = "03273e58dff6f2e5334c526b0dd0100d20e1ac4bfa22dfd904725eef63931e4853";
@@ -21,6 +23,23 @@ const ORANGEME_URL: &str = if cfg!(test) {"ws://0.0.0.0:5702"} else {"ws://air.o
 
 pub fn now() -> u64 {chrono::Utc::now().timestamp_nanos_opt().unwrap() as u64}
 
+///Source of nano seconds since the unix epoch, carried by the Air and the Storage server so tests can move time by hand
+pub trait Clock: Send + Sync + Debug {fn now(&self) -> u64;}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+impl Clock for SystemClock {fn now(&self) -> u64 {now()}}
+
+///Only moves when set or advanced
+#[derive(Debug, Default)]
+pub struct ManualClock(AtomicU64);
+impl ManualClock {
+    pub fn new(time: u64) -> Self {ManualClock(AtomicU64::new(time))}
+    pub fn set(&self, time: u64) {self.0.store(time, Ordering::SeqCst);}
+    pub fn advance(&self, nanos: u64) {self.0.fetch_add(nanos, Ordering::SeqCst);}
+}
+impl Clock for ManualClock {fn now(&self) -> u64 {self.0.load(Ordering::SeqCst)}}
+
 ///30 minutes
 pub const TIMEOUT: u64 = 60_000_000_000;
 
@@ -31,7 +50,8 @@ pub enum Error {
     IdentityExpired,
     MissingPermissions(Vec<Id>),
     ValidationFailed,
-    DecryptionFailed 
+    DecryptionFailed,
+    TicketExpired,
 }
 impl std::error::Error for Error {}
 impl std::fmt::Display for Error {fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {write!(f, "{self:?}")}}
@@ -79,6 +99,8 @@ impl std::str::FromStr for Id {
 pub struct Name(secp256k1::PublicKey);
 impl Name {
     pub fn orange_me() -> Name {Name::from_str(ORANGEME_NAME).unwrap()}
+    ///The key the Name signs with
+    pub fn public_key(&self) -> secp256k1::PublicKey {self.0}
 }
 impl std::fmt::Display for Name {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
@@ -103,6 +125,8 @@ impl Secret {
     pub fn name(&self) -> Name {self.name}
     pub fn path(&self) -> &Vec<Id> {&self.path}
     pub fn harden(&self) -> secp256k1::SecretKey {self.temporary.derive(&self.path)}
+    ///The key behind the Name itself, ignoring the path
+    pub(crate) fn identity_key(&self) -> &secp256k1::SecretKey {&self.temporary}
 
     pub fn new() -> Self {
         let temporary = secp256k1::SecretKey::new();
@@ -157,6 +181,10 @@ impl Identity {
         Encrypted(self.name.0.encrypt(payload))
     }
 
+    pub fn encrypt_padded(&self, _path: &[Id], payload: Vec<u8>, padding: &Padding) -> Encrypted {
+        Encrypted(self.name.0.encrypt_with(payload, padding, None))
+    }
+
     ///If an Identity has a server it means that they actively listen to missives there
     pub fn servers(&self) -> &Vec<Name> {&self.servers}
 
@@ -164,20 +192,56 @@ impl Identity {
     pub fn url(&self) -> &Vec<String> {&self.url}
 
     pub fn get(&self, key: &str) -> Option<&String> {self.data.get(key)}
+
+    ///Leading zero bits a Stamp::Work must have before the home server accepts a missive for this identity
+    pub fn inbox_difficulty(&self) -> u32 {
+        self.get(INBOX_DIFFICULTY).and_then(|d| d.parse().ok()).unwrap_or(crate::stamp::DEFAULT_DIFFICULTY)
+    }
+
+    ///Key of the log the current mailbox is announced on, None until the inbox has been started
+    pub fn inbox_announce(&self) -> Option<secp256k1::PublicKey> {
+        self.get(INBOX_ANNOUNCE).and_then(|k| k.parse().ok())
+    }
+
+    ///Senders that may deliver with a signed Stamp::Allowed instead of doing work
+    pub fn inbox_allowlist(&self) -> Vec<Name> {
+        self.get(INBOX_ALLOWLIST).map(|l| l.split(',').filter_map(|n| Name::from_str(n).ok()).collect()).unwrap_or_default()
+    }
 }
 
-#[derive(Clone, Debug)]
-pub struct Resolver();
+pub const INBOX_DIFFICULTY: &str = "inbox_difficulty";
+pub const INBOX_ALLOWLIST: &str = "inbox_allowlist";
+pub const INBOX_ANNOUNCE: &str = "inbox_announce";
+///Comma separated urls of the Chandler an identity runs, in order of preference
+pub const URL: &str = "chandler_url";
+///Set by a Chandler that answers the ML-KEM offer, the key exchange it combines with ECDH. Clients only open a
+///plain ECDH session with servers that lack it
+pub const HYBRID: &str = "chandler_hybrid";
+///Comma separated names of the servers an identity is at home on
+pub const SERVERS: &str = "home_servers";
+
+///Identity document entries published through it, stands in for the name system. Each Air and Chandler is
+///handed one through its config, so only those sharing a resolver see each others entries
+#[derive(Clone, Debug, Default)]
+pub struct Resolver(Arc<Mutex<HashMap<Name, HashMap<String, String>>>>);
 impl Resolver {
-    pub fn start() -> Self {Resolver()}
+    pub fn start() -> Self {Resolver::default()}
 
     pub async fn resolve(&self, name: Name, _timestamp: Option<u64>) -> Identity {
+        let data = self.0.lock().unwrap().get(&name).cloned().unwrap_or_default();
+        let url = data.get(URL).map(|u| u.split(',').map(str::to_string).collect());
+        let servers = data.get(SERVERS).map(|s| s.split(',').filter_map(|n| Name::from_str(n).ok()).collect());
         if name == Name::orange_me() {
-            Identity{name, url: vec![ORANGEME_URL.to_string()], servers: vec![], data: HashMap::new()}
+            Identity{name, url: url.unwrap_or(vec![ORANGEME_URL.to_string()]), servers: servers.unwrap_or_default(), data}
         } else {
-            Identity{name, url: vec![], servers: vec![Name::orange_me()], data: HashMap::new()}
+            Identity{name, url: url.unwrap_or_default(), servers: servers.unwrap_or(vec![Name::orange_me()]), data}
         }
     }
+
+    ///Sets an entry on the secrets identity document
+    pub async fn publish(&self, secret: &Secret, key: &str, value: String) {
+        self.0.lock().unwrap().entry(secret.name()).or_default().insert(key.to_string(), value);
+    }
 }
 
 #[derive(Debug, Hash, PartialEq, Eq)]
@@ -213,6 +277,20 @@ impl<H: Hash + Debug> AsRef<H> for Signed<H> {fn as_ref(&self) -> &H {&self.payl
 
 
 
+///Sizes ciphertexts are padded up to, anything past the last bucket rounds up to a multiple of it
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Padding(pub Vec<usize>);
+impl Padding {
+    pub fn bucket(&self, len: usize) -> usize {
+        match (self.0.iter().find(|b| **b >= len), self.0.last()) {
+            (Some(bucket), _) => *bucket,
+            (None, Some(last)) if *last > 0 => len.div_ceil(*last) * last,
+            _ => len
+        }
+    }
+}
+impl Default for Padding {fn default() -> Self {Padding(vec![64, 256, 1024, 4096, 16_384, 65_536])}}
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Encrypted(secp256k1::Encrypted);
 
@@ -220,9 +298,15 @@ pub struct Encrypted(secp256k1::Encrypted);
 pub struct Init(secp256k1::Init);//Will contain a secp256k1 key encrypted to the path of the recipient(BSL is an alt to ECDH Key Exchange)
 
 
+pub struct Handshake(secp256k1::Handshake);
+impl Handshake {
+    pub fn session(&self) -> Id {self.0.session()}
+    pub fn finish(self, reply: Reply) -> Result<EncryptionStream, Error> {Ok(EncryptionStream(self.0.finish(reply)?))}
+}
+
 ///Pass init to the remote party
 ///Messages do not have to be received or exchanged one after the other
-///But they do have to be decrypted in the same order they were encrypted
+///and may be decrypted out of order as long as they are within the reorder window
 pub struct EncryptionStream(secp256k1::EncryptionStream);
 impl EncryptionStream {
     pub fn new(recipient: &Identity, _path: &[Id]) -> Result<(Self, Init), Error> {
@@ -230,9 +314,26 @@ impl EncryptionStream {
         Ok((Self(stream), Init(init)))
     }
 
+    ///Also offers an ML-KEM key, pass the recipients Reply to the Handshake to get the stream
+    pub fn hybrid(recipient: &Identity, _path: &[Id]) -> Result<(Handshake, Init), Error> {
+        let (handshake, init) = secp256k1::EncryptionStream::hybrid(&recipient.name.0);
+        Ok((Handshake(handshake), Init(init)))
+    }
+
+    pub fn resume(resumption: &Resumption, ticket: Ticket) -> (Handshake, Resume) {
+        let (handshake, resume) = secp256k1::EncryptionStream::resume(resumption, ticket);
+        (Handshake(handshake), resume)
+    }
+
+    pub fn resumed(key: &secp256k1::SecretKey, resume: Resume, now: u64) -> Result<(Self, Reply), Error> {
+        let (stream, reply) = secp256k1::EncryptionStream::resumed(key, resume, now)?;
+        Ok((Self(stream), reply))
+    }
+
     //Will error if it cannot decrypt shared key.
-    pub fn receive(secret: &Secret, init: Init) -> Result<Self, Error> {
-        Ok(Self(secp256k1::EncryptionStream::receive(&secret.temporary, init.0)))
+    pub fn receive(secret: &Secret, init: Init) -> Result<(Self, Option<Reply>), Error> {
+        let (stream, reply) = secp256k1::EncryptionStream::receive(&secret.temporary, init.0)?;
+        Ok((Self(stream), reply))
     }
 
     pub fn encrypt(&mut self, data: Vec<u8>) -> Message {
@@ -243,6 +344,10 @@ impl EncryptionStream {
         self.0.decrypt(message)
     }
 
+    pub fn session(&self) -> Id {self.0.session()}
+
+    pub fn resumption(&self) -> Resumption {self.0.resumption()}
+
     pub fn split(self) -> (Sink, Drain) {self.0.split()}
 }
 
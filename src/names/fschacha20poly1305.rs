@@ -15,26 +15,32 @@
 //! * **FSChaCha20 operations** +3.7% overhead with pre-allocated buffers.
 //! * **FSChaCha20Poly1305 operations** +1.0% overhead with pre-allocated buffers.  
 
-use chacha20_poly1305::{ChaCha20Poly1305, Key, Nonce};
+use chacha20_poly1305::{ChaCha20, ChaCha20Poly1305, Key, Nonce};
 use core::fmt;
+use std::collections::BTreeMap;
 
-//  /// Message lengths are encoded in three bytes.
-//  const LENGTH_BYTES: u32 = 3;
+/// Message lengths are encoded in four bytes, one more than BIP-324 so a full inbox page fits.
+pub const LENGTH_BYTES: u32 = 4;
 /// Ciphers are re-keyed after 224 messages (or chunks).
 const REKEY_INTERVAL: u64 = 224;
 /// Static four byte prefix used on every re-key.
 const REKEY_INITIAL_NONCE: [u8; 4] = [0xFF, 0xFF, 0xFF, 0xFF];
+/// How far ahead of the next expected message a [`Windowed`] cipher accepts, and how long keys for skipped messages are kept.
+pub const REORDER_WINDOW: u64 = 256;
 
 /// Errors encrypting and decrypting with [`FSChaCha20Poly1305`].
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum Error {
     Decryption(chacha20_poly1305::Error),
+    /// The sequence number is too far ahead, too old, or was already decrypted.
+    Window(u64),
 }
 
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             Error::Decryption(e) => write!(f, "Unable to dycrypt: {e}."),
+            Error::Window(sequence) => write!(f, "Sequence {sequence} is outside the reorder window."),
         }
     }
 }
@@ -43,6 +49,7 @@ impl std::error::Error for Error {
     fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
         match self {
             Error::Decryption(e) => Some(e),
+            Error::Window(_) => None,
         }
     }
 }
@@ -94,6 +101,11 @@ impl FSChaCha20Poly1305 {
         self.message_counter += 1;
     }
 
+    /// Move past the current message without processing it, re-keying with empty associated data.
+    pub fn skip(&mut self) {
+        self.rekey(&[]);
+    }
+
     /// Encrypt the contents in place and return the 16-byte authentication tag.
     ///
     /// # Arguments
@@ -134,43 +146,116 @@ impl FSChaCha20Poly1305 {
     }
 }
 
-//  /// A wrapper over ChaCha20 (unauthenticated) stream cipher which handles automatically changing
-//  /// nonces and re-keying, providing forward secrecy within the session.
-//  ///
-//  /// FSChaCha20 is used for lengths in BIP-324. Should be noted that the lengths are still
-//  /// implicitly authenticated by the message packets.
-//  #[derive(Clone)]
-//  pub struct FSChaCha20 {
-//      key: Key,
-//      block_counter: u32,
-//      chunk_counter: u32,
-//  }
-
-//  impl FSChaCha20 {
-//      pub fn new(key: [u8; 32]) -> Self {
-//          FSChaCha20 {
-//              key: Key::new(key),
-//              block_counter: 0,
-//              chunk_counter: 0,
-//          }
-//      }
-
-//      /// Encrypt or decrypt the 3-byte length encodings.
-//      pub fn crypt(&mut self, chunk: &mut [u8; LENGTH_BYTES as usize]) {
-//          let counter_mod = (self.chunk_counter / REKEY_INTERVAL as u32).to_le_bytes();
-//          let mut nonce = [0u8; 12];
-//          nonce[4..8].copy_from_slice(&counter_mod);
-//          let mut cipher = ChaCha20::new(self.key, Nonce::new(nonce), 0);
-//          cipher.seek(self.block_counter);
-//          cipher.apply_keystream(chunk);
-//          self.block_counter += LENGTH_BYTES;
-//          if (self.chunk_counter + 1).is_multiple_of(REKEY_INTERVAL as u32) {
-//              let mut key_buffer = [0u8; 32];
-//              cipher.seek(self.block_counter);
-//              cipher.apply_keystream(&mut key_buffer);
-//              self.block_counter = 0;
-//              self.key = Key::new(key_buffer);
-//          }
-//          self.chunk_counter += 1;
-//      }
-//  }
+/// A wrapper over ChaCha20 (unauthenticated) stream cipher which handles automatically changing
+/// nonces and re-keying, providing forward secrecy within the session.
+///
+/// FSChaCha20 is used for lengths in BIP-324. Should be noted that the lengths are still
+/// implicitly authenticated by the message packets.
+#[derive(Clone)]
+pub struct FSChaCha20 {
+    key: Key,
+    block_counter: u32,
+    chunk_counter: u32,
+}
+
+impl FSChaCha20 {
+    pub fn new(key: [u8; 32]) -> Self {
+        FSChaCha20 {
+            key: Key::new(key),
+            block_counter: 0,
+            chunk_counter: 0,
+        }
+    }
+
+    /// Encrypt or decrypt the length encodings.
+    pub fn crypt(&mut self, chunk: &mut [u8; LENGTH_BYTES as usize]) {
+        let counter_mod = (self.chunk_counter / REKEY_INTERVAL as u32).to_le_bytes();
+        let mut nonce = [0u8; 12];
+        nonce[4..8].copy_from_slice(&counter_mod);
+        let mut cipher = ChaCha20::new(self.key, Nonce::new(nonce), 0);
+        cipher.seek(self.block_counter);
+        cipher.apply_keystream(chunk);
+        self.block_counter += LENGTH_BYTES;
+        if (self.chunk_counter + 1).is_multiple_of(REKEY_INTERVAL as u32) {
+            let mut key_buffer = [0u8; 32];
+            cipher.seek(self.block_counter);
+            cipher.apply_keystream(&mut key_buffer);
+            self.block_counter = 0;
+            self.key = Key::new(key_buffer);
+        }
+        self.chunk_counter += 1;
+    }
+}
+
+/// Pairs the packet and length ciphers under an explicit sequence number so messages may be decrypted
+/// out of order. The ciphers only ever advance with empty associated data, positioned copies are kept for
+/// skipped sequences until they fall [`REORDER_WINDOW`] behind, and each sequence decrypts at most once.
+#[derive(Clone)]
+pub struct Windowed {
+    next: (FSChaCha20Poly1305, FSChaCha20),
+    sequence: u64,
+    skipped: BTreeMap<u64, (FSChaCha20Poly1305, FSChaCha20)>,
+}
+
+impl Windowed {
+    pub fn new(packet_key: [u8; 32], length_key: [u8; 32]) -> Self {
+        Windowed {
+            next: (FSChaCha20Poly1305::new(packet_key), FSChaCha20::new(length_key)),
+            sequence: 0,
+            skipped: BTreeMap::new(),
+        }
+    }
+
+    /// Returns the ciphers positioned at the current sequence and moves past it.
+    fn advance(next: &mut (FSChaCha20Poly1305, FSChaCha20)) -> (FSChaCha20Poly1305, FSChaCha20) {
+        let current = next.clone();
+        next.0.skip();
+        next.1.crypt(&mut [0; LENGTH_BYTES as usize]);
+        current
+    }
+
+    /// The encrypted length and sequence are authenticated with the packet.
+    fn aad(sequence: u64, length: &[u8; LENGTH_BYTES as usize]) -> Vec<u8> {
+        [&sequence.to_le_bytes()[..], &length[..]].concat()
+    }
+
+    /// Encrypt the length and contents in place, returning the sequence number and authentication tag.
+    pub fn encrypt(&mut self, length: &mut [u8; LENGTH_BYTES as usize], content: &mut [u8]) -> (u64, [u8; 16]) {
+        let sequence = self.sequence;
+        let (mut packets, mut lengths) = Self::advance(&mut self.next);
+        self.sequence += 1;
+        lengths.crypt(length);
+        (sequence, packets.encrypt(&Self::aad(sequence, length), content))
+    }
+
+    /// Decrypt the length and contents in place. State only changes once the packet authenticates.
+    pub fn decrypt(&mut self, sequence: u64, length: &mut [u8; LENGTH_BYTES as usize], content: &mut [u8], tag: [u8; 16]) -> Result<(), Error> {
+        let aad = Self::aad(sequence, length);
+        let (ciphers, ahead) = match sequence.checked_sub(self.sequence) {
+            Some(distance) if distance < REORDER_WINDOW => {
+                let mut next = self.next.clone();
+                let skipped = (self.sequence..sequence).map(|s| (s, Self::advance(&mut next))).collect::<Vec<_>>();
+                (Self::advance(&mut next), Some((next, skipped)))
+            },
+            Some(_) => return Err(Error::Window(sequence)),
+            None => (self.skipped.get(&sequence).cloned().ok_or(Error::Window(sequence))?, None),
+        };
+
+        let (mut packets, mut lengths) = ciphers;
+        packets.decrypt(&aad, content, tag)?;
+        lengths.crypt(length);
+
+        match ahead {
+            Some((next, skipped)) => {
+                self.next = next;
+                self.sequence = sequence + 1;
+                self.skipped.extend(skipped);
+                self.skipped = self.skipped.split_off(&self.sequence.saturating_sub(REORDER_WINDOW));
+            },
+            None => {
+                self.skipped.remove(&sequence);
+            },
+        }
+        Ok(())
+    }
+}
@@ -6,16 +6,24 @@ use serde::{Serialize, Deserialize};
 use serde::ser::Serializer;
 use serde::de::Deserializer;
 
-use super::{TAG, Error, Id};
+use super::{TAG, Error, Id, Padding};
 
 use std::hash::{Hasher, Hash};
 use std::fmt::Debug;
 
-use super::fschacha20poly1305::FSChaCha20Poly1305;
+use super::fschacha20poly1305::{Windowed, LENGTH_BYTES};
 
 pub(crate) use secp256k1::rand;
 use secp256k1::ellswift::Party;
 
+use ml_kem::{MlKem768, KemCore, EncodedSizeUser, Encoded, Ciphertext, B32};
+use ml_kem::kem::{Encapsulate, Decapsulate};
+use rand_core::OsRng;
+use chacha20_poly1305::{ChaCha20Poly1305, Key, Nonce};
+
+type DecapsulationKey = <MlKem768 as KemCore>::DecapsulationKey;
+type EncapsulationKey = <MlKem768 as KemCore>::EncapsulationKey;
+
 #[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
 pub struct Signature(SchnorrSignature);
 impl Signature {
@@ -33,8 +41,16 @@ impl Signature {
 pub struct PublicKey(secp256k1::PublicKey);
 impl PublicKey {
     pub fn verify(&self, signature: &Signature, id: Id) -> Result<(), Error> {signature.verify(self, id)}
-    pub fn encrypt(&self, data: Vec<u8>) -> Encrypted {
-        let (mut stream, init) = EncryptionStream::new(self);
+    pub fn encrypt(&self, data: Vec<u8>) -> Encrypted {self.encrypt_with(data, &Padding::default(), None)}
+
+    ///Pads the ciphertext up to one of the buckets so its size does not reveal the type of data, given the
+    ///recipients KemKey the ECDH secret is combined with an ML-KEM one so the data outlives quantum attackers
+    pub fn encrypt_with(&self, data: Vec<u8>, padding: &Padding, kem: Option<&KemKey>) -> Encrypted {
+        let (mut stream, init) = match kem.and_then(|kem| EncryptionStream::to(self, kem).ok()) {
+            Some(hybrid) => hybrid,
+            None => EncryptionStream::new(self)
+        };
+        stream.pad(padding.clone());
         let message = stream.encrypt(data);
         Encrypted(init, message)
     }
@@ -60,10 +76,18 @@ impl SecretKey {
     pub fn public_key(&self) -> PublicKey {PublicKey(self.0.public_key(SECP256K1))}
     pub fn sign(&self, id: Id) -> Signature {Signature::new(self, id)}
     pub fn decrypt(&self, encrypted: Encrypted) -> Result<Vec<u8>, Error> {
-        let mut stream = EncryptionStream::receive(self, encrypted.0);
+        let (mut stream, _) = EncryptionStream::receive(self, encrypted.0)?;
         stream.decrypt(encrypted.1)
     }
 
+    ///ML-KEM keys derived from this key, anyone holding it can encrypt to its KemKey
+    fn kem(&self) -> (DecapsulationKey, EncapsulationKey) {
+        let seed = |label: &str| B32::from(self.derive(&[Id::hash(label)]).0.secret_bytes());
+        MlKem768::generate_deterministic(&seed("KEM_D"), &seed("KEM_Z"))
+    }
+
+    pub fn kem_key(&self) -> KemKey {KemKey(self.kem().1.as_bytes().to_vec())}
+
     pub fn derive(&self, path: &[Id]) -> Self {
         let mut key = self.0;
         for id in path {
@@ -80,20 +104,53 @@ impl Default for SecretKey {fn default() -> Self {Self::new()}}
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Encrypted(Init, Message);
 
+///Encoded ML-KEM-768 encapsulation key, the post quantum half of a hybrid key exchange
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct KemKey(Vec<u8>);
+impl KemKey {
+    fn decode(&self) -> Result<EncapsulationKey, Error> {
+        Ok(EncapsulationKey::from_bytes(&Encoded::<EncapsulationKey>::try_from(self.0.as_slice()).map_err(|_| Error::InvalidPublicKey)?))
+    }
+
+    fn encapsulate(&self) -> Result<(Vec<u8>, Vec<u8>), Error> {
+        let (ciphertext, shared) = self.decode()?.encapsulate(&mut OsRng).map_err(|_| Error::InvalidPublicKey)?;
+        Ok((ciphertext.to_vec(), shared.to_vec()))
+    }
+}
+
+fn decapsulate(key: &DecapsulationKey, ciphertext: &[u8]) -> Result<Vec<u8>, Error> {
+    let ciphertext = Ciphertext::<MlKem768>::try_from(ciphertext).map_err(|_| Error::DecryptionFailed)?;
+    Ok(key.decapsulate(&ciphertext).map_err(|_| Error::DecryptionFailed)?.to_vec())
+}
+
+///The post quantum half of the key exchange sent alongside the ElligatorSwift key
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum Kem {
+    Classical,
+    ///An ephemeral key offered by a stream initiator, the responder answers with a Reply
+    Offer(KemKey),
+    ///Encapsulated to the recipients KemKey
+    Ciphertext(Vec<u8>),
+}
+
+///The responders ML-KEM ciphertext for an offered key
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Reply(Vec<u8>);
+
 ///The inital data required for decryption
 #[derive(Clone, Debug)]
-pub struct Init(ElligatorSwift);
+pub struct Init(ElligatorSwift, Kem);
 //  impl Init {
 //      fn to_array(&self) -> [u8; 64] {self.0.to_array()}
 //      fn from_array(array: [u8; 64]) -> Self {Self(ElligatorSwift::from_array(array))}
 //  }
 impl Serialize for Init {
-    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {s.serialize_bytes(&self.0.to_array())}
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {(self.0.to_array().to_vec(), &self.1).serialize(s)}
 }
 
 impl<'de> Deserialize<'de> for Init {
-    fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Self, D::Error> {<&[u8]>::deserialize(d).and_then(|b| {
-        Ok(Self(ElligatorSwift::from_array(b.try_into().map_err(|_| serde::de::Error::invalid_length(64, &"Expected a 64 byte array"))?)))
+    fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Self, D::Error> {<(Vec<u8>, Kem)>::deserialize(d).and_then(|(b, kem)| {
+        Ok(Self(ElligatorSwift::from_array(b.try_into().map_err(|_| serde::de::Error::invalid_length(64, &"Expected a 64 byte array"))?), kem))
     })}
 }
 
@@ -126,50 +183,175 @@ impl<'de, H: Hash + Debug + Deserialize<'de>> Deserialize<'de> for Signed<H> {
 impl<H: Hash + Debug> AsRef<H> for Signed<H> {fn as_ref(&self) -> &H {&self.payload}}
 
 
+///Sequence, encrypted length, tag and the padded ciphertext, only the padding bucket is visible on the wire
 #[derive(Serialize, Deserialize, Clone, Debug)]
-pub struct Message([u8; 16], Vec<u8>);
+pub struct Message(u64, [u8; LENGTH_BYTES as usize], [u8; 16], Vec<u8>);
 
-pub struct Sink(FSChaCha20Poly1305);
+fn windowed(shared: &SecretKey, direction: Id) -> Windowed {
+    Windowed::new(shared.derive(&[direction]).0.secret_bytes(), shared.derive(&[direction, Id::hash(LENGTH)]).0.secret_bytes())
+}
+
+pub struct Sink(Windowed, Padding);
 impl Sink {
+    ///Pads the data to a bucket, the real length travels encrypted and is authenticated with the packet
     pub fn encrypt(&mut self, mut data: Vec<u8>) -> Message {
-        let tag = self.0.encrypt(&[], &mut data);
-        Message(tag, data)
+        let mut length = (data.len() as u32).to_le_bytes();
+        data.resize(self.1.bucket(data.len()), 0);
+        let (sequence, tag) = self.0.encrypt(&mut length, &mut data);
+        Message(sequence, length, tag, data)
     }
 }
 
-pub struct Drain(FSChaCha20Poly1305);
+///Messages may arrive in any order within the reorder window, each is only accepted once
+pub struct Drain(Windowed);
 impl Drain {
     pub fn decrypt(&mut self, mut message: Message) -> Result<Vec<u8>, Error> {
-        self.0.decrypt(&[], &mut message.1, message.0).map_err(|_| Error::DecryptionFailed)?;
-        Ok(message.1)
+        self.0.decrypt(message.0, &mut message.1, &mut message.3, message.2).map_err(|_| Error::DecryptionFailed)?;
+        let length = u32::from_le_bytes(message.1) as usize;
+        if length > message.3.len() {Err(Error::DecryptionFailed)?}
+        message.3.truncate(length);
+        Ok(message.3)
     }
 }
 
-//I can use the FsChaCha for length encryption like in BIP324 if needed
-pub struct EncryptionStream(Sink, Drain);
+const LENGTH: &str = "LENGTH";
+
+const RESUMPTION: &str = "RESUMPTION";
+
+///A secret both ends of a stream derive from its keys, a Ticket carries it back to the server on the next connect
+#[derive(Clone, Copy, Debug)]
+pub struct Resumption(SecretKey);
+impl Resumption {
+    ///Seals the secret under the servers ticket key until expires, each ticket has its own key
+    pub fn ticket(&self, key: &SecretKey, expires: u64) -> Ticket {
+        let id = Id::random();
+        let mut sealed = postcard::to_allocvec(&(self.0, expires)).unwrap();
+        let tag = Ticket::cipher(key, id).encrypt(&mut sealed, None);
+        Ticket(id, sealed, tag)
+    }
+}
+
+///Opaque to the client, only the server holding the ticket key can open it
+#[derive(Serialize, Deserialize, Clone, Debug, Hash, PartialEq, Eq)]
+pub struct Ticket(Id, Vec<u8>, [u8; 16]);
+impl Ticket {
+    fn cipher(key: &SecretKey, id: Id) -> ChaCha20Poly1305 {
+        ChaCha20Poly1305::new(Key::new(key.derive(&[id]).0.secret_bytes()), Nonce::new([0; 12]))
+    }
+
+    fn open(self, key: &SecretKey, now: u64) -> Result<Resumption, Error> {
+        let Ticket(id, mut sealed, tag) = self;
+        Self::cipher(key, id).decrypt(&mut sealed, tag, None).map_err(|_| Error::DecryptionFailed)?;
+        let (secret, expires): (SecretKey, u64) = postcard::from_bytes(&sealed).map_err(|_| Error::DecryptionFailed)?;
+        if expires < now {Err(Error::TicketExpired)?}
+        Ok(Resumption(secret))
+    }
+}
+
+///Sent in place of an Init, the nonce keeps the resumed keys apart from every other session using the ticket
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Resume(Ticket, Id);
+
+enum Pending {
+    Kem(DecapsulationKey),
+    Resume,
+}
+
+///An initiator waiting on the responders Reply, an ML-KEM ciphertext or, when resuming, the servers nonce
+pub struct Handshake(SecretKey, Pending);
+impl Handshake {
+    pub fn session(&self) -> Id {EncryptionStream::session_id(&self.0)}
+
+    pub fn finish(self, reply: Reply) -> Result<EncryptionStream, Error> {
+        let mix = match self.1 {
+            Pending::Kem(key) => decapsulate(&key, &reply.0)?,
+            Pending::Resume => reply.0
+        };
+        Ok(EncryptionStream::build(&self.0, Some(&mix), true))
+    }
+}
+
+pub struct EncryptionStream(Sink, Drain, Id, Resumption);
 impl EncryptionStream {
-    pub fn new(recipient: &PublicKey) -> (Self, Init) {
+    fn initiate(recipient: &PublicKey) -> (SecretKey, ElligatorSwift) {
         let key = SecretKey::new();
         let mine = ElligatorSwift::from_pubkey(key.public_key().0);
         let theirs = ElligatorSwift::from_pubkey(recipient.0);
         let ecdh_sk = ElligatorSwift::shared_secret(mine, theirs, key.0, Party::Initiator, Some(TAG.as_bytes()));
-        let shared = SecretKey(secp256k1::SecretKey::from_byte_array(ecdh_sk.to_secret_bytes()).unwrap());
+        (SecretKey(secp256k1::SecretKey::from_byte_array(ecdh_sk.to_secret_bytes()).unwrap()), mine)
+    }
+
+    ///Keys come from the ECDH secret hashed together with the ML-KEM one when there is one, the session id
+    ///only from the ECDH secret so it is known before a hybrid exchange finishes
+    fn build(ecdh: &SecretKey, mix: Option<&[u8]>, initiator: bool) -> Self {
+        let shared = match mix {
+            Some(mix) => SecretKey(secp256k1::SecretKey::from_byte_array(*Id::hash(&[&ecdh.0.secret_bytes() as &[u8], mix].concat())).unwrap()),
+            None => *ecdh
+        };
+        let (send, receive) = if initiator {(Id::MAX, Id::MIN)} else {(Id::MIN, Id::MAX)};
+        let resumption = Resumption(shared.derive(&[Id::hash(RESUMPTION)]));
+        Self(Sink(windowed(&shared, send), Padding::default()), Drain(windowed(&shared, receive)), Self::session_id(ecdh), resumption)
+    }
+
+    pub fn new(recipient: &PublicKey) -> (Self, Init) {
+        let (ecdh, mine) = Self::initiate(recipient);
+        (Self::build(&ecdh, None, true), Init(mine, Kem::Classical))
+    }
 
-        let sender = FSChaCha20Poly1305::new(shared.derive(&[Id::MAX]).0.secret_bytes());
-        let receiver = FSChaCha20Poly1305::new(shared.derive(&[Id::MIN]).0.secret_bytes());
-        (Self(Sink(sender), Drain(receiver)), Init(mine))
+    ///Hybrid encryption to a recipient whose KemKey is known, needs no reply
+    pub fn to(recipient: &PublicKey, kem: &KemKey) -> Result<(Self, Init), Error> {
+        let (ciphertext, shared) = kem.encapsulate()?;
+        let (ecdh, mine) = Self::initiate(recipient);
+        Ok((Self::build(&ecdh, Some(&shared), true), Init(mine, Kem::Ciphertext(ciphertext))))
     }
 
-    pub fn receive(secret: &SecretKey, init: Init) -> Self {
+    ///Offers an ephemeral ML-KEM key, the responder must Reply before the stream can be used
+    pub fn hybrid(recipient: &PublicKey) -> (Handshake, Init) {
+        let (ecdh, mine) = Self::initiate(recipient);
+        let (decapsulation, encapsulation) = MlKem768::generate(&mut OsRng);
+        (Handshake(ecdh, Pending::Kem(decapsulation)), Init(mine, Kem::Offer(KemKey(encapsulation.as_bytes().to_vec()))))
+    }
+
+    ///Skips the ECDH by mixing a fresh nonce into the resumption secret of an earlier session, the chain
+    ///keys are new so that sessions traffic stays protected
+    pub fn resume(resumption: &Resumption, ticket: Ticket) -> (Handshake, Resume) {
+        let nonce = Id::random();
+        (Handshake(resumption.0.derive(&[nonce]), Pending::Resume), Resume(ticket, nonce))
+    }
+
+    ///Opens a ticket sealed under key, our own nonce in the Reply means a replayed Resume gets different keys
+    pub fn resumed(key: &SecretKey, resume: Resume, now: u64) -> Result<(Self, Reply), Error> {
+        let Resume(ticket, nonce) = resume;
+        let resumption = ticket.open(key, now)?;
+        let reply = Id::random();
+        Ok((Self::build(&resumption.0.derive(&[nonce]), Some(reply.as_ref()), false), Reply(reply.to_vec())))
+    }
+
+    ///Follows whichever exchange the initiator chose, returning the Reply owed for an offered key
+    pub fn receive(secret: &SecretKey, init: Init) -> Result<(Self, Option<Reply>), Error> {
         let mine = ElligatorSwift::from_pubkey(secret.public_key().0);
         let ecdh_sk = ElligatorSwift::shared_secret(init.0, mine, secret.0, Party::Responder, Some(TAG.as_bytes()));
-        let shared = SecretKey(secp256k1::SecretKey::from_byte_array(ecdh_sk.to_secret_bytes()).unwrap());
-
-        let receiver = FSChaCha20Poly1305::new(shared.derive(&[Id::MAX]).0.secret_bytes());
-        let sender = FSChaCha20Poly1305::new(shared.derive(&[Id::MIN]).0.secret_bytes());
-        Self(Sink(sender), Drain(receiver))
+        let ecdh = SecretKey(secp256k1::SecretKey::from_byte_array(ecdh_sk.to_secret_bytes()).unwrap());
+
+        match init.1 {
+            Kem::Classical => Ok((Self::build(&ecdh, None, false), None)),
+            Kem::Offer(key) => {
+                let (ciphertext, shared) = key.encapsulate()?;
+                Ok((Self::build(&ecdh, Some(&shared), false), Some(Reply(ciphertext))))
+            },
+            Kem::Ciphertext(ciphertext) => Ok((Self::build(&ecdh, Some(&decapsulate(&secret.kem().0, &ciphertext)?), false), None))
+        }
     }
 
+    fn session_id(shared: &SecretKey) -> Id {Id::hash(&shared.derive(&[Id::hash("SESSION")]).public_key())}
+
+    ///Known only to the two ends of the stream, signing it binds an identity to this stream
+    pub fn session(&self) -> Id {self.2}
+
+    pub fn resumption(&self) -> Resumption {self.3}
+
+    pub fn pad(&mut self, padding: Padding) {self.0.1 = padding;}
+
     pub fn encrypt(&mut self, data: Vec<u8>) -> Message {
         self.0.encrypt(data)
     }
@@ -211,7 +393,8 @@ fn stream() {
     let msg2 = b"Sorry just saw these messages".to_vec();
     let msg3 = b"I also just don't want to talk to you...".to_vec();
 
-    let mut receiver_stream = EncryptionStream::receive(&remote, init);
+    let (mut receiver_stream, _) = EncryptionStream::receive(&remote, init).unwrap();
+    assert_eq!(stream.session(), receiver_stream.session());
 
     let message0 = stream.encrypt(msg0.clone());
     let message1 = stream.encrypt(msg1.clone());
@@ -223,4 +406,75 @@ fn stream() {
 
     let message3 = receiver_stream.encrypt(msg3.clone());
     assert_eq!(stream.decrypt(message3), Ok(msg3));
+
+    let message4 = stream.encrypt(msg0.clone());
+    assert_eq!(message4.3.len(), Padding::default().bucket(msg0.len()));
+    assert_eq!(receiver_stream.decrypt(message4), Ok(msg0.clone()));
+}
+
+#[test]
+fn reorder() {
+    let remote = SecretKey::new();
+    let (mut stream, init) = EncryptionStream::new(&remote.public_key());
+    let (mut receiver_stream, _) = EncryptionStream::receive(&remote, init.clone()).unwrap();
+
+    let messages = (0..300u32).map(|i| i.to_le_bytes().to_vec()).collect::<Vec<_>>();
+    let encrypted = messages.iter().map(|m| stream.encrypt(m.clone())).collect::<Vec<_>>();
+
+    assert_eq!(receiver_stream.decrypt(encrypted[2].clone()), Ok(messages[2].clone()));
+    assert_eq!(receiver_stream.decrypt(encrypted[0].clone()), Ok(messages[0].clone()));
+    assert!(receiver_stream.decrypt(encrypted[0].clone()).is_err());
+    assert_eq!(receiver_stream.decrypt(encrypted[1].clone()), Ok(messages[1].clone()));
+    //Past a rekey
+    assert_eq!(receiver_stream.decrypt(encrypted[230].clone()), Ok(messages[230].clone()));
+    assert_eq!(receiver_stream.decrypt(encrypted[3].clone()), Ok(messages[3].clone()));
+    assert_eq!(receiver_stream.decrypt(encrypted[299].clone()), Ok(messages[299].clone()));
+
+    //Too far ahead, then fell out of the window
+    let (mut late, _) = EncryptionStream::receive(&remote, init).unwrap();
+    assert!(late.decrypt(encrypted[299].clone()).is_err());
+    assert!(late.decrypt(encrypted[255].clone()).is_ok());
+    assert!(late.decrypt(encrypted[299].clone()).is_ok());
+    assert!(late.decrypt(encrypted[10].clone()).is_err());
+    assert!(late.decrypt(encrypted[50].clone()).is_ok());
+}
+
+#[test]
+fn hybrid() {
+    let remote = SecretKey::new();
+    let (handshake, init) = EncryptionStream::hybrid(&remote.public_key());
+    let (mut receiver_stream, reply) = EncryptionStream::receive(&remote, init).unwrap();
+    assert_eq!(handshake.session(), receiver_stream.session());
+    let mut stream = handshake.finish(reply.unwrap()).unwrap();
+
+    let message = b"hello".to_vec();
+    assert_eq!(receiver_stream.decrypt(stream.encrypt(message.clone())), Ok(message.clone()));
+
+    let encrypted = remote.public_key().encrypt_with(message.clone(), &Padding::default(), Some(&remote.kem_key()));
+    assert!(matches!(encrypted.0.1, Kem::Ciphertext(_)));
+    assert_eq!(remote.decrypt(encrypted), Ok(message.clone()));
+    assert!(SecretKey::new().decrypt(remote.public_key().encrypt_with(message, &Padding::default(), Some(&remote.kem_key()))).is_err());
+}
+
+#[test]
+fn resumption() {
+    let (remote, tickets) = (SecretKey::new(), SecretKey::new());
+    let (handshake, init) = EncryptionStream::hybrid(&remote.public_key());
+    let (server, reply) = EncryptionStream::receive(&remote, init).unwrap();
+    let client = handshake.finish(reply.unwrap()).unwrap();
+    let ticket = server.resumption().ticket(&tickets, 10);
+
+    let (handshake, resume) = EncryptionStream::resume(&client.resumption(), ticket.clone());
+    let (mut server, reply) = EncryptionStream::resumed(&tickets, resume.clone(), 5).unwrap();
+    assert_eq!(handshake.session(), server.session());
+    let mut client = handshake.finish(reply).unwrap();
+    let message = b"hello again".to_vec();
+    assert_eq!(server.decrypt(client.encrypt(message.clone())), Ok(message.clone()));
+
+    //A replayed Resume gets new keys
+    let (mut replayed, _) = EncryptionStream::resumed(&tickets, resume.clone(), 5).unwrap();
+    assert!(replayed.decrypt(server.encrypt(message)).is_err());
+
+    assert!(EncryptionStream::resumed(&tickets, resume.clone(), 11).is_err());
+    assert!(EncryptionStream::resumed(&SecretKey::new(), resume, 5).is_err());
 }
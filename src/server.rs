@@ -1,225 +1,778 @@
-use crate::names::{Secret, EncryptionStream};
-use crate::storage::{Storage, Request, Response};
+use crate::names::{Secret, EncryptionStream, Signed, Id, Handshake, Resumption, Ticket, Reply, Clock, SystemClock};
+use crate::names::secp256k1::SecretKey;
+use crate::storage::{Storage, StorageBackend, Request, Response};
 
-use futures_util::{StreamExt, SinkExt};
+use futures_util::StreamExt;
 
 use crossfire::{MAsyncTx, AsyncTx, AsyncRx, spsc, mpsc};
 
-use tokio::net::{TcpListener, TcpStream};
+use tokio::net::TcpListener;
 use tokio::spawn;
 
-use tokio_tungstenite::{accept_hdr_async, tungstenite, WebSocketStream};
-use tungstenite::handshake::server::{Request as TungRequest, Response as TungResponse, ErrorResponse};
-use tungstenite::protocol::Message;
-use tungstenite::http::StatusCode;
-
-use futures_util::stream::{SplitStream, FuturesUnordered};
+use axum::{Router, Extension};
+use axum::routing::{get, post};
+use axum::extract::{State, Path, Query, ws::WebSocketUpgrade};
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response as HttpResponse};
+use axum::body::Bytes;
+use hyper_util::rt::{TokioIo, TokioExecutor};
+use hyper_util::server::conn::auto::Builder;
+use hyper_util::service::TowerToHyperService;
+
+use futures_util::stream::FuturesUnordered;
 use std::pin::Pin;
 
-use tokio_tungstenite::{connect_async, MaybeTlsStream};
-use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use std::collections::{HashMap, BTreeMap};
+
+use crate::names::{Error, Resolver, Identity, Name, Sink, Drain, URL, HYBRID};
 
-use futures_util::stream::{SplitSink};
+mod limits;
+pub use limits::Limits;
+use limits::{Limiter, Client};
 
-use std::collections::HashMap;
-use std::collections::hash_map::Entry;
+mod tls;
+pub use tls::Tls;
+use tokio_rustls::TlsAcceptor;
+use rustls::RootCertStore;
+use tokio::io::{AsyncRead, AsyncWrite};
 
-use crate::names::{Error, Resolver, Name, Sink, Drain};
+mod skew;
+use skew::Skew;
+
+mod transport;
+#[cfg(test)]
+pub(crate) mod simulator;
+use transport::{Transport, Polls, FrameTx, FrameRx, Dial, REPLY, POLL_IDLE, LOOPBACK};
 
-type S = WebSocketStream<MaybeTlsStream<TcpStream>>;
-type Open = (Name, AsyncTx<spsc::One<Result<Connection, Error>>>);
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::time::{sleep, interval, Duration, Instant};
+use tokio::sync::watch;
+use serde::{Serialize, Deserialize, de::DeserializeOwned};
+
+type Open = (Name, bool, AsyncTx<spsc::One<Result<Connection, Error>>>);//Anonymous
 type Outgoing = (Vec<u8>, Responder);
 type Responder = AsyncTx<spsc::Array<Response>>;
 type RReceiver = AsyncRx<spsc::Array<Response>>;
+type Tickets = Arc<Mutex<HashMap<Name, (Resumption, Ticket)>>>;
 type PBFut<T> = Pin<Box<dyn Future<Output = T> + Send>>;
 
-pub struct Receiver(AsyncRx<spsc::Array<Response>>);
+///Longest throttle a Send is held back for before it is given up on
+pub const MAX_RETRY: u64 = 30_000_000_000;//30 seconds
+
+///How long a request waits on its first response before it is encrypted and sent again
+const RETRANSMIT: u64 = 1_000_000_000;//1 second
+///How long a subscription goes between resends, the server replays what it already answered so a lost
+///notification is not waited on forever
+const RESUBSCRIBE: u64 = 10_000_000_000;//10 seconds
+///While the server is silent only the oldest request is resent, after 1, 2, 4... retransmits then every PROBE
+const PROBE: u32 = 32;
+///Requests a socket keeps the responses to, so one sent again is answered from them instead of run twice
+const REPLAYED: usize = 1024;
+
+///Throttled requests are resent once the server says to retry however long that is, callers only see the
+///eventual response. A Send is handed back instead since its stamp would have expired, the caller stamps it
+///again before resending
+pub struct Receiver(RReceiver, Connection, Request);
 impl Receiver {
     pub async fn recv(&mut self) -> Response {
-        self.0.recv().await.unwrap()
+        loop {match self.0.recv().await.unwrap() {
+            Response::Throttled(wait) if !matches!(self.2, Request::Send(..)) => {
+                println!("Throttled for {wait}ns");
+                sleep(Duration::from_nanos(wait)).await;
+                self.0 = self.1.submit(&self.2).await;
+            },
+            response => break response
+        }}
     }
 }
 
+///The session id is only known once the handshake finishes, a resumed session has a different one
 #[derive(Debug, Clone)]
-pub struct Connection(MAsyncTx<mpsc::List<Outgoing>>);
+pub struct Connection(MAsyncTx<mpsc::List<Outgoing>>, watch::Receiver<Option<Id>>);
 impl Connection {
+    ///Proves control of the secrets Name, at its path, to the server for the rest of this connection so its
+    ///requests are charged to the Name instead of the IP
+    pub async fn authenticate(&self, secret: &Secret) -> Response {
+        let session = self.1.clone().wait_for(Option::is_some).await.unwrap().unwrap();
+        self.send(Request::Authenticate(Signed::new(secret, session), secret.path().clone())).await.recv().await
+    }
+
     pub async fn send(&self, request: Request) -> Receiver {
+        Receiver(self.submit(&request).await, self.clone(), request)
+    }
+
+    async fn submit(&self, request: &Request) -> RReceiver {
         let (tx, rx): (_, AsyncRx<_>) = spsc::build(spsc::Array::new(request.max_responses()));
-        self.0.send((postcard::to_allocvec(&request).unwrap(), tx)).await.unwrap();
-        Receiver(rx)
+        self.0.send((postcard::to_allocvec(request).unwrap(), tx)).await.unwrap();
+        rx
+    }
+}
+
+///A request the server has not yet ended the responses to
+struct Owed {
+    frame: Vec<u8>,
+    due: Instant,
+    ///Its round trip includes the wait before resending, so it says nothing about the servers offset
+    resent: bool,
+}
+
+///Shared by a connections writer and reader so requests are resent until they are answered
+#[derive(Default)]
+struct Unanswered {
+    requests: BTreeMap<u64, Owed>,
+    ///A response arrived since the writer last looked, the server is reachable
+    heard: bool,
+}
+impl Unanswered {
+    ///The frames to send again. While nothing is heard only the oldest goes, backing off, so a long partition
+    ///does not push the stream past the servers reorder window
+    fn due(&mut self, quiet: &mut u32) -> Vec<Vec<u8>> {
+        let now = Instant::now();
+        let mut due = self.requests.values_mut().filter(|owed| owed.due <= now).peekable();
+        let count = if std::mem::take(&mut self.heard) || due.peek().is_none() {*quiet = 0; usize::MAX} else {
+            *quiet += 1;
+            (quiet.is_power_of_two() || *quiet % PROBE == 0) as usize
+        };
+        due.take(count).map(|owed| {
+            (owed.due, owed.resent) = (now + Duration::from_nanos(RETRANSMIT), true);
+            owed.frame.clone()
+        }).collect()
+    }
+
+    ///Notes a response to the request, returning if it was resent. Nothing more is owed once its responses end,
+    ///however many there were
+    fn answer(&mut self, index: u64, end: bool) -> bool {
+        let Some(owed) = self.requests.get_mut(&index) else {return false};
+        let resent = owed.resent;
+        owed.due = Instant::now() + Duration::from_nanos(RESUBSCRIBE);
+        if end {self.requests.remove(&index);}
+        resent
     }
 }
 
 #[derive(Debug, Clone)]
-pub struct Purser(MAsyncTx<mpsc::List<Open>>);
+pub struct Purser(MAsyncTx<mpsc::List<Open>>, Skew);
 impl Purser {
-    pub fn start(resolver: Resolver) -> Self {
+    ///wss:// servers must present a certificate chaining to one of the roots, every connection goes through the
+    ///SOCKS5 proxy when there is one. Round trips are timed on the clock to estimate each servers offset. With
+    ///hybrid set every session combines ML-KEM with ECDH, servers that do not answer it are refused
+    pub fn start(resolver: Resolver, roots: &RootCertStore, proxy: Option<SocketAddr>, clock: Arc<dyn Clock>, hybrid: bool) -> Self {
         let (tx, rx) = mpsc::build(mpsc::List::new());
-        spawn(Self::run(resolver, rx));
-        Purser(tx)
+        let skew = Skew::new(clock);
+        spawn(Self::run(resolver, Transport::new(roots, proxy), skew.clone(), hybrid, rx));
+        Purser(tx, skew)
     }
 
+    ///Nano seconds the servers clock is ahead of ours, None until it has answered a request
+    pub fn offset(&self, server: &Name) -> Option<i64> {self.1.offset(server)}
+
+    ///Our clock corrected to the servers, so pending timestamps match the ones it will confirm with
+    pub fn now(&self, server: &Name) -> u64 {self.1.now(server)}
+
     pub async fn connect(&self, name: Name) -> Result<Connection, Error> {
         let (tx, rx): (_, AsyncRx<_>) = spsc::build(spsc::One::new());
-        self.0.send((name, tx)).await.unwrap();
+        self.0.send((name, false, tx)).await.unwrap();
+        rx.recv().await.unwrap()
+    }
+
+    ///A fresh connection that is never shared or authenticated, so the server cannot link its requests to
+    ///any other connection of ours
+    pub async fn anonymous(&self, name: Name) -> Result<Connection, Error> {
+        let (tx, rx): (_, AsyncRx<_>) = spsc::build(spsc::One::new());
+        self.0.send((name, true, tx)).await.unwrap();
         rx.recv().await.unwrap()
     }
 
-    async fn run(resolver: Resolver, rx: AsyncRx<mpsc::List<Open>>) {
+    async fn run(resolver: Resolver, transport: Transport, skew: Skew, hybrid: bool, rx: AsyncRx<mpsc::List<Open>>) {
         let mut open_connections = HashMap::<Name, Connection>::new();
-        while let Ok((name, responder)) = rx.recv().await {
+        let tickets = Tickets::default();
+        while let Ok((name, anonymous, responder)) = rx.recv().await {
             //TODO: Do timeout based cleanup after a connection isnt used
             //open_connections.retain(|_, c| c.0.get_tx_count() > 1);
             let identity = resolver.resolve(name, None).await;
 
-            let result = match open_connections.entry(name) {
-                Entry::Occupied(occupied) => Ok(occupied.get().clone()),
-                Entry::Vacant(vacant) => {
-                    let (tx, rx) = mpsc::build(mpsc::List::new());
-                    spawn(async move {
-                        let (stream, init) = EncryptionStream::new(&identity, &[]).unwrap();
-                        let url = identity.url().first().unwrap();
-                        let (sink, drain) = stream.split();
-                        //TODO: Be more resiliant to bad connections, try the secondary url
-                        //from the names etc. And automatically handle major errors such as
-                        //downed servers or attacking air servers.
-                        let mut request = url.into_client_request().unwrap();
-                        request.headers_mut().insert("X-Public-Key", hex::encode(postcard::to_allocvec(&init).unwrap()).parse().unwrap());
-                        let (ws_stream, _) = connect_async(request).await.unwrap();
-                        let (write, read) = ws_stream.split();
-                        let (stx, srx) = spsc::build(spsc::List::new());
-                        spawn(Self::write(sink, rx, stx, write));
-                        spawn(Self::read(drain, srx, read));
-                    });
-                    Ok(vacant.insert(Connection(tx)).clone())
+            //Anonymous connections never resume, a ticket would link them to the session it came from
+            let result = if anonymous {Ok(Self::open(identity, transport.clone(), skew.clone(), hybrid, None))} else {
+                match open_connections.get(&name).filter(|c| !c.0.is_disconnected()) {
+                    Some(connection) => Ok(connection.clone()),
+                    None => {
+                        let connection = Self::open(identity, transport.clone(), skew.clone(), hybrid, Some(tickets.clone()));
+                        open_connections.insert(name, connection.clone());
+                        Ok(connection)
+                    }
                 }
             };
             let _ = responder.send(result).await;
         }
     }
 
-    async fn write(mut sink: Sink, rx: AsyncRx<mpsc::List<Outgoing>>, stx: AsyncTx<mpsc::List<Responder>>, mut write: SplitSink<S, Message>) {
-        while let Ok((request, responder)) = rx.recv().await {
-            
-            let _ = stx.send(responder).await;
-            //TODO: Again be more resilant to bad connections
-            write.send(Message::Binary(postcard::to_allocvec(&sink.encrypt(request)).unwrap().into())).await.unwrap();
+    ///Resumes with the ticket from the last connection to this name when there is one, then asks for the next
+    fn open(identity: Identity, transport: Transport, skew: Skew, hybrid: bool, tickets: Option<Tickets>) -> Connection {
+        let (tx, rx) = mpsc::build(mpsc::List::new());
+        let (session, srx) = watch::channel(None);
+        let connection = Connection(tx, srx);
+        let ticketing = connection.clone();
+        spawn(async move {
+            let Some(url) = identity.url().first() else {println!("Could Not Connect: {} Has No Url", identity.name()); return};
+            //TODO: Be more resiliant to bad connections, try the secondary url
+            //from the names etc. And automatically handle major errors such as
+            //downed servers or attacking air servers.
+            let ticket = tickets.as_ref().and_then(|tickets| tickets.lock().unwrap().remove(&identity.name()));
+            let resumed = match ticket {
+                Some((resumption, ticket)) => {
+                    let (handshake, resume) = EncryptionStream::resume(&resumption, ticket);
+                    Self::handshake(url, &transport, "X-Resume", &resume, handshake).await
+                },
+                None => None
+            };
+            //The ticket may have expired or the server restarted, fall back to a full exchange. Only a server whose
+            //identity does not say it answers the ML-KEM offer gets a plain ECDH session, and only when we do not
+            //require one, so stripping the reply off the handshake fails the connection instead of downgrading it
+            let connected = match resumed {
+                Some(resumed) => Some(resumed),
+                None if hybrid || identity.get(HYBRID).is_some() => {
+                    let (handshake, init) = EncryptionStream::hybrid(&identity, &[]).unwrap();
+                    Self::handshake(url, &transport, "X-Public-Key", &init, handshake).await
+                },
+                None => Self::classical(url, &transport, &identity).await
+            };
+            //Dropping the requests marks the connection disconnected, the next connect opens a new one
+            let Some((frames_tx, frames_rx, stream)) = connected else {println!("Could Not Connect To {}", identity.name()); return};
+            let _ = session.send(Some(stream.session()));
+            let resumption = stream.resumption();
+            let (sink, drain) = stream.split();
+            let (stx, srx) = spsc::build(spsc::List::new());
+            let unanswered = Arc::new(Mutex::new(Unanswered::default()));
+            spawn(Self::write(sink, rx, stx, frames_tx, skew.clone(), unanswered.clone()));
+            spawn(Self::read(drain, srx, frames_rx, skew, identity.name(), unanswered));
+
+            if let Some(tickets) = tickets
+                && let Response::Ticket(ticket) = ticketing.send(Request::Ticket).await.recv().await {
+                tickets.lock().unwrap().insert(identity.name(), (resumption, ticket));
+            }
+        });
+        connection
+    }
+
+    ///Connects sending the handshake in the given header and finishes it with the servers reply
+    async fn handshake<T: Serialize>(url: &str, transport: &Transport, header: &'static str, init: &T, handshake: Handshake) -> Option<(FrameTx, FrameRx, EncryptionStream)> {
+        let (tx, rx, reply) = transport.connect(url, header, hex::encode(postcard::to_allocvec(init).unwrap())).await?;
+        let reply = postcard::from_bytes(&hex::decode(reply).ok()?).ok()?;
+        Some((tx, rx, handshake.finish(reply).ok()?))
+    }
+
+    ///Plain ECDH, the server needs no reply to finish it
+    async fn classical(url: &str, transport: &Transport, identity: &Identity) -> Option<(FrameTx, FrameRx, EncryptionStream)> {
+        let (stream, init) = EncryptionStream::new(identity, &[]).ok()?;
+        let (tx, rx, _) = transport.connect(url, "X-Public-Key", hex::encode(postcard::to_allocvec(&init).unwrap())).await?;
+        Some((tx, rx, stream))
+    }
+
+    ///Requests carry their index so responses still find their responder when frames arrive out of order, and
+    ///so the server recognizes one that is encrypted and sent again after its frame or response was lost
+    async fn write(mut sink: Sink, rx: AsyncRx<mpsc::List<Outgoing>>, stx: AsyncTx<mpsc::List<(Responder, u64)>>, frames: FrameTx, skew: Skew, unanswered: Arc<Mutex<Unanswered>>) {
+        let mut index: u64 = 0;
+        let (mut retransmit, mut quiet) = (interval(Duration::from_nanos(RETRANSMIT)), 0);
+        loop {
+            let send = tokio::select! {
+                outgoing = rx.recv() => {
+                    let Ok((request, responder)) = outgoing else {break};
+                    let _ = stx.send((responder, skew.local())).await;
+                    let mut frame = postcard::to_allocvec(&index).unwrap();
+                    frame.extend(request);
+                    let owed = Owed{frame: frame.clone(), due: Instant::now() + Duration::from_nanos(RETRANSMIT), resent: false};
+                    unanswered.lock().unwrap().requests.insert(index, owed);
+                    index += 1;
+                    vec![frame]
+                },
+                _ = retransmit.tick() => unanswered.lock().unwrap().due(&mut quiet)
+            };
+            for frame in send {
+                if frames.send(postcard::to_allocvec(&sink.encrypt(frame)).unwrap()).await.is_err() {return}
+            }
         }
     }
 
-    async fn read(mut drain: Drain, srx: AsyncRx<mpsc::List<Responder>>, mut read: SplitStream<S>) {
-        //TODO: Clean up pending requests that are completed or at least ignored
-        let mut pending: HashMap<usize, Responder> = HashMap::new();
+    ///The first response to each request is timed against when it was sent to sample the servers offset, the
+    ///transport closes when this returns
+    async fn read(mut drain: Drain, srx: AsyncRx<mpsc::List<(Responder, u64)>>, frames: FrameRx, skew: Skew, server: Name, unanswered: Arc<Mutex<Unanswered>>) {
+        let mut pending: HashMap<usize, (Responder, Option<u64>, usize)> = HashMap::new();
         let mut index = 0;
 
         loop {
             tokio::select! {
-                Ok(responder) = srx.recv() => {
-                    pending.insert(index, responder);
+                biased;
+                //The writer only stops once every Connection is dropped, nothing is left waiting on a response
+                sent = srx.recv() => {
+                    let Ok((responder, sent)) = sent else {break};
+                    pending.insert(index, (responder, Some(sent), 0));
                     index += 1;
                 }
-                Some(ws_result) = read.next() => {
-                    //TODO: Again be more resilant to bad connections
-                    match ws_result.unwrap() {
-                        Message::Binary(payload) => {
-                            let (index, response): (u64, Response) = postcard::from_bytes(&drain.decrypt(postcard::from_bytes(&payload).unwrap()).unwrap()).unwrap();
-                            let _ = pending.get_mut(&(index as usize)).unwrap().send(response).await;
-                        },
-                        m => panic!("Unexpected Message: {m:?}")
+                Ok(payload) = frames.recv() => {
+                    //Duplicated or replayed frames do not decrypt a second time
+                    let Ok(frame) = postcard::from_bytes(&payload) else {continue};
+                    let Ok(message) = drain.decrypt(frame) else {continue};
+                    let Ok((index, n, response)) = postcard::from_bytes::<(u64, usize, Option<Response>)>(&message) else {continue};
+                    unanswered.lock().unwrap().heard = true;
+                    //A resent request is answered again with everything so far, each response is passed on once. One
+                    //the server no longer remembers is told so whatever it had received
+                    let replayed = matches!(response, Some(Response::Replayed));
+                    if let Some((responder, sent, received)) = pending.get_mut(&(index as usize)) && (n == *received || replayed) {
+                        *received += 1;
+                        let resent = unanswered.lock().unwrap().answer(index, response.is_none() || replayed);
+                        let Some(response) = response else {continue};
+                        if let (Some(sent), Some(timestamp), false) = (sent.take(), response.timestamp(), resent) {skew.sample(server, sent, timestamp);}
+                        let _ = responder.send(response).await;
                     }
                 }
                 else => break,
             }
 
-            pending.retain(|_, responder| responder.get_tx_count() > 0);
+            //Nothing is resent for a response no one is waiting on
+            let mut owed = unanswered.lock().unwrap();
+            pending.retain(|index, (responder, ..)| {
+                if responder.is_disconnected() {owed.requests.remove(&(*index as u64));}
+                !responder.is_disconnected()
+            });
         }
     }
 }
 
+#[derive(Clone, Debug)]
+pub struct ChandlerConfig {
+    ///How long, in nano seconds, unacknowledged missives are kept, None keeps them forever
+    pub retention: Option<u64>,
+    pub limits: Limits,
+    ///Where to accept websocket and long poll connections, None only serves in process
+    pub address: Option<SocketAddr>,
+    ///Also serve loopback://{name} to Pursers in this process, through channels instead of sockets
+    pub loopback: bool,
+    ///Terminates TLS so clients can reach the server through proxies that block plain websockets, long polling
+    ///over HTTP is always served on the same port for networks that block the upgrade entirely
+    pub tls: Option<Tls>,
+    ///Timestamps slots and missives and ages stamps, tickets and limits
+    pub clock: Arc<dyn Clock>,
+    ///Where the urls are published and the identities of clients and senders are looked up
+    pub resolver: Resolver,
+}
+impl Default for ChandlerConfig {fn default() -> Self {
+    ChandlerConfig{retention: Some(RETENTION), limits: Limits::default(), address: Some(SocketAddr::from(([0, 0, 0, 0], 5702))), loopback: false, tls: None, clock: Arc::new(SystemClock), resolver: Resolver::start()}
+}}
+
+///30 days
+pub const RETENTION: u64 = 30 * 24 * 3_600_000_000_000;
+///1 day
+pub const TICKET_LIFETIME: u64 = 24 * 3_600_000_000_000;
+
+///How many response frames a long poll client has received
+#[derive(Deserialize)]
+struct PollCursor {
+    #[serde(default)]
+    after: u64,
+}
+
 #[derive(Clone)]
 pub struct Chandler {
     storage: Storage,
     secret: Secret,
+    resolver: Resolver,
+    limiter: Limiter,
+    connections: Arc<AtomicU64>,
+    ///Seals resumption tickets, a new key every start so a restart invalidates them
+    tickets: SecretKey,
+    polls: Polls,
 }
 
 impl Chandler {
-    pub async fn start(secret: Secret) {
-        let storage = Storage::start(&secret);
-        let chandler = Chandler{storage, secret};
+    ///Publishes the urls it serves at on its identity, returns without serving if the TLS files are unusable
+    pub async fn start<B: StorageBackend>(secret: Secret, backend: B, config: ChandlerConfig) {
+        if let Some((chandler, listener)) = Self::bind(secret, backend, config).await {
+            chandler.accept(listener).await
+        }
+    }
 
-        let listener = TcpListener::bind("0.0.0.0:5702").await.unwrap();
-        while let Ok((stream, _)) = listener.accept().await {
-            spawn(chandler.clone().upgrade(stream));
+    ///Starts serving in process and binds the address, clients can connect as soon as it returns since the
+    ///urls are already published. None if the TLS files are unusable
+    async fn bind<B: StorageBackend>(secret: Secret, backend: B, config: ChandlerConfig) -> Option<(Self, Option<(TcpListener, Option<TlsAcceptor>)>)> {
+        let acceptor = match config.tls.as_ref().map(Tls::acceptor).transpose() {
+            Ok(acceptor) => acceptor,
+            Err(e) => {println!("Invalid TLS: {e}"); return None}
+        };
+        let (resolver, name) = (config.resolver, secret.name());
+        let storage = Storage::start(&secret, resolver.clone(), backend, config.retention, config.clock.clone());
+        let (limiter, polls) = (Limiter::new(config.limits, config.clock.clone()), Polls::new(config.clock));
+        let chandler = Chandler{storage, secret: secret.clone(), resolver: resolver.clone(), limiter: limiter.clone(), connections: Arc::default(), tickets: SecretKey::new(), polls};
+        spawn(async move {
+            let mut interval = interval(Duration::from_secs(3_600));
+            loop {interval.tick().await; limiter.clean();}
+        });
+
+        let polls = chandler.polls.clone();
+        spawn(async move {
+            let mut interval = interval(Duration::from_nanos(POLL_IDLE));
+            loop {interval.tick().await; polls.clean();}
+        });
+
+        let mut urls = vec![];
+        if config.loopback {
+            urls.push(format!("{LOOPBACK}{name}"));
+            spawn(chandler.clone().loopback(transport::listen(&name.to_string())));
+        }
+        let listener = match config.address {
+            Some(address) => Some(TcpListener::bind(address).await.unwrap()),
+            None => None
+        };
+        if let Some(listener) = &listener {
+            let scheme = if config.tls.is_some() {"wss"} else {"ws"};
+            urls.push(format!("{scheme}://{}", listener.local_addr().unwrap()));
         }
+        resolver.publish(&secret, HYBRID, "ML-KEM-768".to_string()).await;
+        resolver.publish(&secret, URL, urls.join(",")).await;
+        Some((chandler, listener.map(|l| (l, acceptor))))
     }
 
-    async fn upgrade(mut self, stream: TcpStream) {
-        let mut public = None;
-        #[allow(clippy::result_large_err)]
-        match accept_hdr_async(stream, |req: &TungRequest, response: TungResponse| {
-            match req.headers().get("X-Public-Key").and_then(|x| EncryptionStream::receive(&self.secret, postcard::from_bytes(&hex::decode(x.to_str().ok()?).ok()?).ok()?).ok()) {
-                Some(init) => {
-                    public = Some(init);
-                    Ok(response)
+    ///Accepts websocket and long poll connections until the listener fails, forever when only serving in process
+    async fn accept(self, listener: Option<(TcpListener, Option<TlsAcceptor>)>) {
+        let Some((listener, acceptor)) = listener else {return std::future::pending().await};
+        let router = Router::new()
+            .route("/", get(Self::websocket))
+            .route("/poll", post(Self::open_poll))
+            .route("/poll/{token}", post(Self::push).get(Self::poll))
+            .with_state(self);
+        while let Ok((stream, address)) = listener.accept().await {
+            let (router, acceptor) = (router.clone().layer(Extension(address)), acceptor.clone());
+            spawn(async move {match acceptor {
+                Some(acceptor) => match acceptor.accept(stream).await {
+                    Ok(stream) => Self::serve(stream, router).await,
+                    Err(e) => println!("Invalid TLS: {e}")
                 },
-                None => {
-                    let mut resp = ErrorResponse::new(Some("Invalid/Missing X-Public-Key".to_string()));
-                    *resp.status_mut() = StatusCode::BAD_REQUEST;
-                    Err(resp)
-                }
-            }
-        }).await {
-            Ok(stream) => self.socket(stream, public.unwrap()).await,
-            Err(e) => println!("Invalid Socket: {e}")
+                None => Self::serve(stream, router).await
+            }});
+        }
+    }
+
+    async fn serve<T: AsyncRead + AsyncWrite + Unpin + Send + 'static>(stream: T, router: Router) {
+        let service = TowerToHyperService::new(router);
+        if let Err(e) = Builder::new(TokioExecutor::new()).serve_connection_with_upgrades(TokioIo::new(stream), service).await {
+            println!("Connection Errored: {e}");
+        }
+    }
+
+    ///Checks the request against every limit that applies to this client, answering in place of storage if any is exceeded
+    fn admit(&self, clients: &[Client], request: &Request) -> Result<(), Response> {
+        if request.size() > self.limiter.limits().max_payload {
+            Err(Response::InvalidRequest("Payload Too Large".to_string()))?
+        }
+        let slots = matches!(request, Request::Create(_) | Request::Log(_)) as u64;
+        self.limiter.charge(clients, request.size() as u64, slots).map_err(Response::Throttled)
+    }
+
+    ///A response the Chandler gives itself without involving storage
+    fn reply(response: Response) -> RReceiver {
+        let (tx, rx) = spsc::build(spsc::Array::new(1));
+        let _ = tx.try_send(response);
+        rx
+    }
+
+    ///Verifies the client signed this connections session id, returning the Name it now acts as
+    async fn authenticate(&self, session: Id, signed: Signed<Id>, path: Vec<Id>) -> Result<Name, Response> {
+        if signed.payload != session {Err(Response::InvalidRequest("Wrong Session".to_string()))?}
+        let identity = self.resolver.resolve(signed.signer, None).await;
+        signed.verify(&identity, &path).map_err(|e| Response::InvalidSignature(e.to_string()))?;
+        Ok(signed.signer)
+    }
+
+    fn header<T: DeserializeOwned>(headers: &HeaderMap, name: &str) -> Option<T> {
+        postcard::from_bytes(&hex::decode(headers.get(name)?.to_str().ok()?).ok()?).ok()
+    }
+
+    ///Follows the key exchange in the X-Resume or X-Public-Key header, the same for every transport
+    fn accept(&self, headers: &HeaderMap) -> Result<(EncryptionStream, Option<Reply>), HttpResponse> {
+        let accepted = match Self::header(headers, "X-Resume") {
+            Some(resume) => EncryptionStream::resumed(&self.tickets, resume, self.storage.now()).ok().map(|(stream, reply)| (stream, Some(reply))),
+            None => Self::header(headers, "X-Public-Key").and_then(|init| EncryptionStream::receive(&self.secret, init).ok())
+        };
+        accepted.ok_or_else(|| (StatusCode::BAD_REQUEST, "Invalid/Missing X-Public-Key or X-Resume").into_response())
+    }
+
+    fn respond(response: impl IntoResponse, reply: Option<Reply>) -> HttpResponse {
+        let mut response = response.into_response();
+        if let Some(reply) = reply {
+            response.headers_mut().insert(REPLY, hex::encode(postcard::to_allocvec(&reply).unwrap()).parse().unwrap());
+        }
+        response
+    }
+
+    async fn websocket(State(mut chandler): State<Self>, Extension(address): Extension<SocketAddr>, headers: HeaderMap, upgrade: WebSocketUpgrade) -> HttpResponse {
+        match chandler.accept(&headers) {
+            Ok((encryption, reply)) => Self::respond(upgrade.on_upgrade(move |socket| async move {
+                let (incoming, outgoing) = transport::accept(socket);
+                chandler.socket(incoming, outgoing, encryption, address).await
+            }), reply),
+            Err(response) => response
+        }
+    }
+
+    ///Opens a long poll session, answering with its token
+    async fn open_poll(State(mut chandler): State<Self>, Extension(address): Extension<SocketAddr>, headers: HeaderMap) -> HttpResponse {
+        match chandler.accept(&headers) {
+            Ok((encryption, reply)) => {
+                let (token, incoming, outgoing) = chandler.polls.open();
+                spawn(async move {chandler.socket(incoming, outgoing, encryption, address).await});
+                Self::respond(token.to_string(), reply)
+            },
+            Err(response) => response
+        }
+    }
+
+    ///Answers in process Pursers, the session runs over channels with the same framing as a socket
+    async fn loopback(self, dials: AsyncRx<mpsc::List<Dial>>) {
+        while let Ok((header, handshake, responder)) = dials.recv().await {
+            let mut headers = HeaderMap::new();
+            let Ok(value) = handshake.parse() else {let _ = responder.send(None).await; continue};
+            headers.insert(header, value);
+            let connected = self.accept(&headers).ok().map(|(encryption, reply)| {
+                let ((tx, rx), (outgoing, incoming)) = transport::pipe();
+                let mut chandler = self.clone();
+                spawn(async move {chandler.socket(incoming, outgoing, encryption, SocketAddr::from(([127, 0, 0, 1], 0))).await});
+                (tx, rx, reply.map(|reply| hex::encode(postcard::to_allocvec(&reply).unwrap())).unwrap_or_default())
+            });
+            let _ = responder.send(connected).await;
+        }
+    }
+
+    ///Takes one encrypted request frame
+    async fn push(State(chandler): State<Self>, Path(token): Path<Id>, frame: Bytes) -> StatusCode {
+        chandler.polls.push(token, frame.to_vec()).await
+    }
+
+    ///Answers with the postcard encoded response frames after the ones the client says it has
+    async fn poll(State(chandler): State<Self>, Path(token): Path<Id>, Query(cursor): Query<PollCursor>) -> HttpResponse {
+        match chandler.polls.poll(token, cursor.after).await {
+            Ok(frames) => postcard::to_allocvec(&frames).unwrap().into_response(),
+            Err(status) => status.into_response()
         }
     }
 
     //Each Socket needs to handle request sequentially, paralization could be used to prepare
     //decrypted/deserialized responses for the read/write step
-    async fn socket(&mut self, stream: WebSocketStream<TcpStream>, encryption: EncryptionStream) {
-        let (mut write, mut read) = stream.split();
+    async fn socket(&mut self, incoming: FrameRx, outgoing: FrameTx, encryption: EncryptionStream, address: SocketAddr) {
+        //Everyone behind a reverse proxy, and every client in this process, shares a loopback address
+        let mut clients = vec![Client::Connection(self.connections.fetch_add(1, Ordering::Relaxed))];
+        if !address.ip().is_loopback() {clients.push(Client::Ip(address.ip()));}
+        let mut session = None;
+        let id = encryption.session();
+        let resumption = encryption.resumption();
         let (mut sink, mut drain) = encryption.split();
-        let mut index: usize = 0;
-        let mut futures: FuturesUnordered<PBFut<(usize, Response, RReceiver)>> = FuturesUnordered::new();
+        let mut futures: FuturesUnordered<PBFut<(usize, usize, Option<Response>, RReceiver)>> = FuturesUnordered::new();
+        //Responses sent to each request so far and whether they have ended, below forgotten they were evicted
+        let (mut answered, mut forgotten) = (BTreeMap::<usize, (Vec<Response>, bool)>::new(), 0);
 
         loop {
             tokio::select! {
                 biased;
-                Some((index, response, receiver)) = futures.next() => {
-                    let _ = write.send(Message::Binary(postcard::to_allocvec(&sink.encrypt(postcard::to_allocvec(&(index, response)).unwrap())).unwrap().into())).await;
-                    if receiver.get_tx_count() > 0 {
-                        futures.push(Box::pin(async move {(index, receiver.recv().await.unwrap(), receiver)}) as _);
+                //Once the storage drops its end the client is sent None, so it knows to stop resending the request
+                Some((index, n, response, receiver)) = futures.next() => {
+                    if let Some((responses, finished)) = answered.get_mut(&index) {
+                        match &response {
+                            Some(response) => responses.push(response.clone()),
+                            None => *finished = true
+                        }
+                    }
+                    let _ = outgoing.send(Self::frame(&mut sink, index, n, response.as_ref())).await;
+                    if response.is_some() {
+                        futures.push(Box::pin(async move {(index, n + 1, receiver.recv().await.ok(), receiver)}) as _);
                     }
                 },
-                Some(ws_result) = read.next() => {
-                    match ws_result {
-                        Ok(message) => match message {
-                            Message::Binary(payload) => {
-                                let request = postcard::from_bytes(&drain.decrypt(postcard::from_bytes(&payload).unwrap()).unwrap()).unwrap();
-                                let srx = self.storage.request(request).await;
-                                futures.push(Box::pin(async move {(index, srx.recv().await.unwrap(), srx)}) as _);
-                                index += 1;
-                            },
-                            Message::Close(_) => {
-                                println!("Client disconnected");
-                                break;
+                frame = incoming.recv() => {
+                    let Ok(payload) = frame else {
+                        println!("Client disconnected");
+                        break;
+                    };
+                    //Duplicated or replayed frames do not decrypt a second time
+                    let Ok(frame) = postcard::from_bytes(&payload) else {continue};
+                    let Ok(message) = drain.decrypt(frame) else {continue};
+                    let Ok((index, request)) = postcard::from_bytes::<(usize, Request)>(&message) else {continue};
+                    //Sent again after a lost frame, answered with what it has had so far rather than run twice
+                    if let Some((responses, finished)) = answered.get(&index) {
+                        let end = finished.then_some(None);
+                        for (n, response) in responses.iter().map(Some).chain(end).enumerate() {
+                            let _ = outgoing.send(Self::frame(&mut sink, index, n, response)).await;
+                        }
+                        continue;
+                    }
+                    if index < forgotten {
+                        let _ = outgoing.send(Self::frame(&mut sink, index, 0, Some(&Response::Replayed))).await;
+                        continue;
+                    }
+                    answered.insert(index, (vec![], false));
+                    if answered.len() > REPLAYED {
+                        let evicted = answered.iter().find(|(_, (_, done))| *done).or(answered.first_key_value()).map(|(i, _)| *i).unwrap();
+                        answered.remove(&evicted);
+                        forgotten = forgotten.max(evicted + 1);
+                    }
+                    let srx = match self.admit(&clients, &request) {
+                        Ok(()) => match request {
+                            Request::Authenticate(signed, path) => {
+                                let response = match self.authenticate(id, signed, path).await {
+                                    Ok(name) => {
+                                        clients.truncate(1);
+                                        clients.push(Client::Name(name));
+                                        session = Some(name);
+                                        Response::Authenticated(name)
+                                    },
+                                    Err(response) => response
+                                };
+                                Self::reply(response)
                             },
-                            e => {println!("Ignored Request: {e:?}");}
-                        },
-                        Err(e) => {
-                            println!("Client Errored: {:?}", e);
-                            break;
+                            Request::Ticket => Self::reply(Response::Ticket(resumption.ticket(&self.tickets, self.storage.now() + TICKET_LIFETIME))),
+                            request => self.storage.request(request, session).await
                         },
-                    }
+                        Err(response) => Self::reply(response)
+                    };
+                    futures.push(Box::pin(async move {(index, 0, srx.recv().await.ok(), srx)}) as _);
                 },
-                else => {println!("unknown");}
             }
         }
     }
+
+    ///The nth response to the request at index, None once there are no more
+    fn frame(sink: &mut Sink, index: usize, n: usize, response: Option<&Response>) -> Vec<u8> {
+        postcard::to_allocvec(&sink.encrypt(postcard::to_allocvec(&(index, n, response)).unwrap())).unwrap()
+    }
+}
+
+///Serves the secret in process only, on the resolver and clock given. Its url is published by the time this
+///returns, so tests connect straight away instead of waiting for it to appear
+#[cfg(test)]
+pub(crate) async fn loopback_server(secret: &Secret, resolver: &Resolver, clock: Arc<dyn Clock>) -> (Chandler, Name) {
+    let config = ChandlerConfig{address: None, loopback: true, clock, resolver: resolver.clone(), ..Default::default()};
+    let (chandler, _) = Chandler::bind(secret.clone(), crate::storage::Memory::default(), config).await.unwrap();
+    (chandler, secret.name())
 }
 
 #[cfg(test)]
 mod test {
+    use super::*;
+    use crate::storage::Memory;
+    use crate::names::secp256k1::Signed as KeySigned;
+
+    #[test]
+    fn unanswered() {
+        //A stream is resent however many responses it has had until the server ends it
+        let mut unanswered = Unanswered::default();
+        unanswered.requests.insert(0, Owed{frame: vec![], due: Instant::now(), resent: false});
+        for _ in 0..3 {assert!(!unanswered.answer(0, false));}
+        assert!(unanswered.requests.contains_key(&0));
+        unanswered.answer(0, true);
+        assert!(unanswered.requests.is_empty());
+    }
+
+    #[tokio::test]
+    async fn loopback() {
+        let (servers, resolver) = ((0..2).map(|_| Secret::new()).collect::<Vec<_>>(), Resolver::start());
+        for secret in &servers {loopback_server(secret, &resolver, Arc::new(SystemClock)).await;}
+
+        let key = SecretKey::new();
+        let clients = (0..3).map(|_| Purser::start(resolver.clone(), &RootCertStore::empty(), None, Arc::new(SystemClock), true)).collect::<Vec<_>>();
+        let first = clients[0].connect(servers[0].name()).await.unwrap();
+        let response = first.send(Request::Create(KeySigned::new(&key, b"hello".to_vec()))).await.recv().await;
+        assert!(matches!(response, Response::Create(..)));
+
+        for client in &clients {
+            match client.connect(servers[0].name()).await.unwrap().send(Request::Read(key.public_key(), false)).await.recv().await {
+                Response::Read(_, _, Some((_, payload))) => assert_eq!(payload, b"hello".to_vec()),
+                response => panic!("Unexpected Response {response:?}")
+            }
+            match client.connect(servers[1].name()).await.unwrap().send(Request::Read(key.public_key(), false)).await.recv().await {
+                Response::Read(_, _, None) => {},
+                response => panic!("Unexpected Response {response:?}")
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn skew() {
+        const HOUR: u64 = 3_600_000_000_000;
+        let server = Secret::new();
+        let clock = Arc::new(crate::names::ManualClock::new(crate::names::now() + HOUR));
+        let resolver = Resolver::start();
+        loopback_server(&server, &resolver, clock.clone()).await;
+
+        let purser = Purser::start(resolver, &RootCertStore::empty(), None, Arc::new(SystemClock), true);
+        assert_eq!(purser.offset(&server.name()), None);
+        let connection = purser.connect(server.name()).await.unwrap();
+        connection.send(Request::Read(SecretKey::new().public_key(), false)).await.recv().await;
+        let offset = purser.offset(&server.name()).unwrap();
+        assert!(offset.abs_diff(HOUR as i64) < 1_000_000_000);
+        assert!(purser.now(&server.name()).abs_diff(clock.now()) < 1_000_000_000);
+    }
+
+    #[tokio::test]
+    async fn classical() {
+        //Stands in for a server that never answers the ML-KEM offer
+        let (server, resolver) = (Secret::new(), Resolver::start());
+        let dials = transport::listen("classical");
+        resolver.publish(&server, URL, format!("{LOOPBACK}classical")).await;
+        let secret = server.clone();
+        spawn(async move {
+            while let Ok((_, handshake, responder)) = dials.recv().await {
+                let init = postcard::from_bytes(&hex::decode(handshake).unwrap()).unwrap();
+                let (stream, reply) = EncryptionStream::receive(&secret, init).unwrap();
+                let ((tx, rx), (outgoing, incoming)) = transport::pipe();
+                let _ = responder.send(Some((tx, rx, String::new()))).await;
+                if reply.is_some() {continue}
+                let (mut sink, mut drain) = stream.split();
+                while let Ok(payload) = incoming.recv().await {
+                    let message = drain.decrypt(postcard::from_bytes(&payload).unwrap()).unwrap();
+                    let (index, _): (u64, Request) = postcard::from_bytes(&message).unwrap();
+                    for response in [(index, 0usize, Some(Response::InvalidRequest("Classical".to_string()))), (index, 1, None)] {
+                        outgoing.send(postcard::to_allocvec(&sink.encrypt(postcard::to_allocvec(&response).unwrap())).unwrap()).await.unwrap();
+                    }
+                }
+            }
+        });
+
+        //Its identity does not say it answers ML-KEM so a client that does not require it settles for ECDH
+        let purser = Purser::start(resolver.clone(), &RootCertStore::empty(), None, Arc::new(SystemClock), false);
+        let connection = purser.anonymous(server.name()).await.unwrap();
+        let response = connection.send(Request::Read(SecretKey::new().public_key(), false)).await.recv().await;
+        assert_eq!(response, Response::InvalidRequest("Classical".to_string()));
+
+        //A client that requires it is refused, and so is every client once the identity says it is answered,
+        //which is all a reply stripped off the handshake looks like
+        let strict = Purser::start(resolver.clone(), &RootCertStore::empty(), None, Arc::new(SystemClock), true);
+        assert!(strict.anonymous(server.name()).await.unwrap().1.wait_for(Option::is_some).await.is_err());
+        resolver.publish(&server, HYBRID, "ML-KEM-768".to_string()).await;
+        assert!(purser.anonymous(server.name()).await.unwrap().1.wait_for(Option::is_some).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn long_poll() {
+        let server = Secret::new();
+        let resolver = Resolver::start();
+        let config = ChandlerConfig{address: Some(SocketAddr::from(([127, 0, 0, 1], 0))), resolver: resolver.clone(), ..Default::default()};
+        let (chandler, listener) = Chandler::bind(server.clone(), Memory::default(), config).await.unwrap();
+        spawn(chandler.accept(listener));
+        let url = resolver.resolve(server.name(), None).await.url()[0].clone();
+        //The websocket upgrade refuses an http url, so the Purser falls back to long polling
+        resolver.publish(&server, URL, url.replacen("ws://", "http://", 1)).await;
+
+        let purser = Purser::start(resolver, &RootCertStore::empty(), None, Arc::new(SystemClock), true);
+        let connection = purser.connect(server.name()).await.unwrap();
+        let key = SecretKey::new();
+        let mut subscription = connection.send(Request::Read(key.public_key(), true)).await;
+        assert!(matches!(subscription.recv().await, Response::Read(_, _, None)));
+        let response = connection.send(Request::Create(KeySigned::new(&key, b"polled".to_vec()))).await.recv().await;
+        assert!(matches!(response, Response::Create(..)));
+        match subscription.recv().await {
+            Response::Read(_, _, Some((_, payload))) => assert_eq!(payload, b"polled".to_vec()),
+            response => panic!("Unexpected Response {response:?}")
+        }
+    }
+
   //use super::*;
   //use crate::storage::{Request, Response, Compare, Metadata};
   //use crate::names::{Name, secp256k1::{SecretKey, Signed as KeySigned}, Resolver, Id, Signed, Secret};
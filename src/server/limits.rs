@@ -0,0 +1,108 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+
+use crate::names::{Clock, Name};
+
+const SECOND: u64 = 1_000_000_000;
+const DAY: u64 = 24 * 3_600 * SECOND;
+
+///Per client limits, every request is charged to its connection and to the Name it authenticated as, or to
+///its IP until it does. Loopback addresses are not charged, they are shared by everyone behind a local proxy
+#[derive(Clone, Debug)]
+pub struct Limits {
+    pub requests_per_second: u32,
+    pub bytes_per_day: u64,
+    ///Slots a client may create per day
+    pub slots_per_day: u64,
+    ///Largest payload accepted in a single Create or Send
+    pub max_payload: usize,
+}
+impl Default for Limits {fn default() -> Self {
+    Limits{requests_per_second: 100, bytes_per_day: 1_000_000_000, slots_per_day: 100_000, max_payload: 1_000_000}
+}}
+
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+pub enum Client {
+    Connection(u64),
+    Ip(IpAddr),
+    Name(Name),
+}
+
+#[derive(Default)]
+struct Usage {
+    tokens: u64,
+    refilled: u64,
+    day: u64,
+    bytes: u64,
+    slots: u64,
+}
+
+#[derive(Clone)]
+pub struct Limiter(Limits, Arc<Mutex<HashMap<Client, Usage>>>, Arc<dyn Clock>);
+impl Limiter {
+    pub fn new(limits: Limits, clock: Arc<dyn Clock>) -> Self {Limiter(limits, Arc::default(), clock)}
+
+    pub fn limits(&self) -> &Limits {&self.0}
+
+    ///Charges the request to every client, returns how many nano seconds to wait before retrying if any client
+    ///is over its limit, nothing is charged in that case
+    pub fn charge(&self, clients: &[Client], bytes: u64, slots: u64) -> Result<(), u64> {
+        let time = self.2.now();
+        let (day, capacity) = (time / DAY, self.0.requests_per_second as u64 * SECOND);
+        let mut usage = self.1.lock().unwrap();
+        for client in clients {
+            let usage = usage.entry(*client).or_insert_with(|| Usage{tokens: capacity, refilled: time, day, ..Default::default()});
+            usage.tokens = usage.tokens.saturating_add(time.saturating_sub(usage.refilled).saturating_mul(self.0.requests_per_second as u64)).min(capacity);
+            usage.refilled = time;
+            if usage.day != day {
+                usage.day = day;
+                usage.bytes = 0;
+                usage.slots = 0;
+            }
+            if usage.tokens < SECOND {
+                Err((SECOND - usage.tokens) / (self.0.requests_per_second.max(1) as u64))?
+            }
+            if usage.bytes + bytes > self.0.bytes_per_day || usage.slots + slots > self.0.slots_per_day {
+                Err((day + 1) * DAY - time)?
+            }
+        }
+        for client in clients {
+            let usage = usage.get_mut(client).unwrap();
+            usage.tokens -= SECOND;
+            usage.bytes += bytes;
+            usage.slots += slots;
+        }
+        Ok(())
+    }
+
+    ///Forgets clients that have been idle for a day
+    pub fn clean(&self) {
+        let day = self.2.now() / DAY;
+        self.1.lock().unwrap().retain(|_, usage| usage.day + 1 >= day);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::names::ManualClock;
+
+    #[test]
+    fn throttle() {
+        let clock = Arc::new(ManualClock::new(DAY));
+        let limiter = Limiter::new(Limits{requests_per_second: 2, bytes_per_day: 10, slots_per_day: 1, max_payload: 10}, clock.clone());
+        let clients = [Client::Connection(0)];
+        assert_eq!(limiter.charge(&clients, 5, 1), Ok(()));
+        assert!(limiter.charge(&clients, 0, 1).is_err());
+        assert!(limiter.charge(&[Client::Connection(1)], 11, 0).is_err());
+        assert_eq!(limiter.charge(&[Client::Connection(1)], 10, 0), Ok(()));
+        assert_eq!(limiter.charge(&clients, 0, 0), Ok(()));
+        assert_eq!(limiter.charge(&clients, 0, 0), Err(SECOND / 2));
+
+        clock.advance(SECOND / 2);
+        assert_eq!(limiter.charge(&clients, 0, 0), Ok(()));
+        clock.advance(DAY);
+        assert_eq!(limiter.charge(&clients, 0, 1), Ok(()));
+    }
+}
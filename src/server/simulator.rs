@@ -0,0 +1,175 @@
+//! A seeded stand in for the network between Pursers and in process Chandlers. Every fault is drawn from one
+//! splitmix64 stream, so on a current thread runtime with paused time a failing schedule replays from its seed.
+
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+
+use tokio::spawn;
+use tokio::time::{sleep, Duration};
+use tokio_util::sync::CancellationToken;
+
+use crossfire::{AsyncRx, mpsc};
+
+use super::transport::{self, Dial, FrameTx, FrameRx, LOOPBACK};
+
+///Chances, from 0 to 1, of each fault happening to a frame
+#[derive(Clone, Debug, Default)]
+pub struct Faults {
+    pub drop: f64,
+    pub duplicate: f64,
+    ///Held back behind the frames sent after it
+    pub reorder: f64,
+    ///Most milliseconds a frame is delayed
+    pub delay: u64,
+}
+
+#[derive(Default)]
+struct State {
+    seed: u64,
+    faults: Faults,
+    partitioned: HashSet<String>,
+    crashed: HashSet<String>,
+    ///Cancelled when the server crashes, ending every connection to it
+    alive: HashMap<String, CancellationToken>,
+}
+
+#[derive(Clone, Default)]
+pub struct Network(Arc<Mutex<State>>);
+impl Network {
+    pub fn new(seed: u64, faults: Faults) -> Self {
+        Network(Arc::new(Mutex::new(State{seed, faults, ..Default::default()})))
+    }
+
+    ///The next draw, uniform in [0, 1)
+    pub fn next(&self) -> f64 {
+        let mut state = self.0.lock().unwrap();
+        state.seed = state.seed.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = state.seed;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        (z ^ (z >> 31)) as f64 / u64::MAX as f64
+    }
+
+    pub fn faults(&self, faults: Faults) {self.0.lock().unwrap().faults = faults;}
+
+    ///Serves the Chandler at loopback://{target} through the network, returning the url to publish for it
+    pub fn route(&self, target: &str) -> String {
+        let host = format!("sim/{target}");
+        spawn(self.clone().serve(target.to_string(), transport::listen(&host)));
+        format!("{LOOPBACK}{host}")
+    }
+
+    ///Frames to and from the target are lost until healed, connections stay open
+    pub fn partition(&self, target: &str) {self.0.lock().unwrap().partitioned.insert(target.to_string());}
+    pub fn heal(&self, target: &str) {self.0.lock().unwrap().partitioned.remove(target);}
+
+    ///Closes every connection to the target and refuses new ones until it restarts
+    pub fn crash(&self, target: &str) {
+        let mut state = self.0.lock().unwrap();
+        state.crashed.insert(target.to_string());
+        if let Some(alive) = state.alive.remove(target) {alive.cancel();}
+    }
+    pub fn restart(&self, target: &str) {self.0.lock().unwrap().crashed.remove(target);}
+
+    async fn serve(self, target: String, dials: AsyncRx<mpsc::List<Dial>>) {
+        while let Ok((header, handshake, responder)) = dials.recv().await {
+            let crashed = self.0.lock().unwrap().crashed.contains(&target);
+            let connected = if crashed {None} else {transport::dial(&target, header, handshake).await};
+            let connected = connected.map(|(tx, rx, reply)| {
+                let ((client_tx, client_rx), (ours_tx, ours_rx)) = transport::pipe();
+                let alive = self.0.lock().unwrap().alive.entry(target.clone()).or_default().clone();
+                spawn(self.clone().relay(target.clone(), ours_rx, tx, alive.clone()));
+                spawn(self.clone().relay(target.clone(), rx, ours_tx, alive));
+                (client_tx, client_rx, reply)
+            });
+            let _ = responder.send(connected).await;
+        }
+    }
+
+    async fn relay(self, target: String, from: FrameRx, to: FrameTx, alive: CancellationToken) {
+        loop {
+            let frame = tokio::select! {
+                _ = alive.cancelled() => break,
+                frame = from.recv() => match frame {
+                    Ok(frame) => frame,
+                    Err(_) => break
+                }
+            };
+            let (faults, partitioned) = {
+                let state = self.0.lock().unwrap();
+                (state.faults.clone(), state.partitioned.contains(&target))
+            };
+            if partitioned || self.next() < faults.drop {continue}
+            let copies = if self.next() < faults.duplicate {2} else {1};
+            let mut delay = (self.next() * faults.delay as f64) as u64;
+            if self.next() < faults.reorder {delay += faults.delay.max(1);}
+            let to = to.clone();
+            spawn(async move {
+                sleep(Duration::from_millis(delay)).await;
+                for _ in 0..copies {let _ = to.send(frame.clone()).await;}
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::names::{Secret, Resolver, URL, secp256k1::{SecretKey, Signed as KeySigned}};
+    use crate::server::{Purser, loopback_server};
+    use crate::storage::{Request, Response};
+    use rustls::RootCertStore;
+
+    #[test]
+    fn schedule() {
+        let draws = |seed| {let network = Network::new(seed, Faults::default()); (0..8).map(|_| network.next()).collect::<Vec<_>>()};
+        assert_eq!(draws(1), draws(1));
+        assert_ne!(draws(1), draws(2));
+        assert!(draws(3).iter().all(|d| (0.0..1.0).contains(d)));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn faulty() {
+        let secret = Secret::new();
+        let name = secret.name().to_string();
+        let resolver = Resolver::start();
+        loopback_server(&secret, &resolver, Arc::new(crate::names::SystemClock)).await;
+
+        let network = Network::new(7, Faults{drop: 0.2, duplicate: 0.3, reorder: 0.3, delay: 50});
+        resolver.publish(&secret, URL, network.route(&name)).await;
+        let purser = Purser::start(resolver, &RootCertStore::empty(), None, Arc::new(crate::names::SystemClock), true);
+        let connection = purser.connect(secret.name()).await.unwrap();
+
+        let keys = (0..20).map(|_| SecretKey::new()).collect::<Vec<_>>();
+        for (i, key) in keys.iter().enumerate() {
+            let response = connection.send(Request::Create(KeySigned::new(key, vec![i as u8]))).await.recv().await;
+            assert!(matches!(response, Response::Create(..)));
+        }
+        let mut reads = vec![];
+        for key in &keys {reads.push(connection.send(Request::Read(key.public_key(), false)).await);}
+        for (i, mut read) in reads.into_iter().enumerate() {
+            match read.recv().await {
+                Response::Read(_, _, Some((_, payload))) => assert_eq!(payload, vec![i as u8]),
+                response => panic!("Unexpected Response {response:?}")
+            }
+        }
+
+        //Sent while partitioned, answered once it heals, and the subscription still hears of the write
+        let key = SecretKey::new();
+        let mut subscription = connection.send(Request::Read(key.public_key(), true)).await;
+        assert!(matches!(subscription.recv().await, Response::Read(_, _, None)));
+        network.partition(&name);
+        let mut create = connection.send(Request::Create(KeySigned::new(&key, b"healed".to_vec()))).await;
+        sleep(Duration::from_secs(60)).await;
+        network.heal(&name);
+        assert!(matches!(create.recv().await, Response::Create(..)));
+        match subscription.recv().await {
+            Response::Read(_, _, Some((_, payload))) => assert_eq!(payload, b"healed".to_vec()),
+            response => panic!("Unexpected Response {response:?}")
+        }
+
+        //Refused while crashed
+        network.crash(&name);
+        assert!(transport::dial(&format!("sim/{name}"), "X-Public-Key", String::new()).await.is_none());
+    }
+}
@@ -0,0 +1,68 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+
+use crate::names::{Clock, Name};
+
+///Samples kept per server, older ones fall out so a changed offset is picked up
+const SAMPLES: usize = 8;
+
+///Estimates each servers clock offset from the timestamps on its responses. The server answered somewhere
+///between sending and receiving, so each sample is off by at most half its round trip and the shortest wins
+#[derive(Clone, Debug)]
+#[allow(clippy::type_complexity)]
+pub struct Skew(Arc<Mutex<HashMap<Name, VecDeque<(u64, i64)>>>>, Arc<dyn Clock>);//Round trip, Offset
+impl Skew {
+    pub fn new(clock: Arc<dyn Clock>) -> Self {Skew(Arc::default(), clock)}
+
+    pub fn local(&self) -> u64 {self.1.now()}
+
+    ///Records a response the server stamped with its time to a request sent at the local time
+    pub fn sample(&self, server: Name, sent: u64, timestamp: u64) {
+        let received = self.local().max(sent);
+        let offset = timestamp as i64 - (sent / 2 + received / 2) as i64;
+        let mut samples = self.0.lock().unwrap();
+        let samples = samples.entry(server).or_default();
+        if samples.len() == SAMPLES {samples.pop_front();}
+        samples.push_back((received - sent, offset));
+    }
+
+    ///Nano seconds the servers clock is ahead of ours, None until it has answered a request
+    pub fn offset(&self, server: &Name) -> Option<i64> {
+        self.0.lock().unwrap().get(server)?.iter().min_by_key(|(rtt, _)| *rtt).map(|(_, offset)| *offset)
+    }
+
+    ///Our time corrected to the servers clock
+    pub fn now(&self, server: &Name) -> u64 {
+        self.local().saturating_add_signed(self.offset(server).unwrap_or_default())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::names::{ManualClock, Secret};
+
+    #[test]
+    fn offset() {
+        let clock = Arc::new(ManualClock::new(1_000_000));
+        let skew = Skew::new(clock.clone());
+        let server = Secret::new().name();
+        assert_eq!(skew.offset(&server), None);
+        assert_eq!(skew.now(&server), 1_000_000);
+
+        //Ahead by 5000, answered halfway through a 100 round trip
+        clock.advance(100);
+        skew.sample(server, 1_000_000, 1_005_050);
+        assert_eq!(skew.offset(&server), Some(5_000));
+
+        //A slow round trip is less certain and does not replace the estimate
+        clock.advance(10_000);
+        skew.sample(server, 1_000_100, 1_000_000);
+        assert_eq!(skew.offset(&server), Some(5_000));
+        assert_eq!(skew.now(&server), 1_015_100);
+
+        //Behind
+        for _ in 0..SAMPLES {skew.sample(server, clock.now(), clock.now() - 2_000);}
+        assert_eq!(skew.offset(&server), Some(-2_000));
+    }
+}
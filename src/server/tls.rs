@@ -0,0 +1,77 @@
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use rustls::{ClientConfig, ServerConfig, RootCertStore};
+use rustls::crypto::ring::default_provider;
+use tokio_rustls::TlsAcceptor;
+
+///PEM files the Chandler terminates TLS with, clients then connect to its wss:// url
+#[derive(Clone, Debug)]
+pub struct Tls {
+    ///The certificate chain, leaf first
+    pub certificates: PathBuf,
+    pub key: PathBuf,
+}
+impl Tls {
+    ///Errors if either file cannot be read or parsed, or the key does not belong to the certificate
+    pub fn acceptor(&self) -> Result<TlsAcceptor, io::Error> {
+        let certificates = rustls_pemfile::certs(&mut BufReader::new(File::open(&self.certificates)?)).collect::<Result<Vec<_>, _>>()?;
+        let key = rustls_pemfile::private_key(&mut BufReader::new(File::open(&self.key)?))?
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "No Private Key In File"))?;
+        let config = ServerConfig::builder_with_provider(Arc::new(default_provider())).with_safe_default_protocol_versions().map_err(io::Error::other)?
+            .with_no_client_auth().with_single_cert(certificates, key).map_err(io::Error::other)?;
+        Ok(TlsAcceptor::from(Arc::new(config)))
+    }
+}
+
+///Validates wss:// and https:// servers against the roots
+pub fn client(roots: &RootCertStore) -> Arc<ClientConfig> {
+    let config = ClientConfig::builder_with_provider(Arc::new(default_provider())).with_safe_default_protocol_versions().unwrap()
+        .with_root_certificates(roots.clone()).with_no_client_auth();
+    Arc::new(config)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use futures_util::{SinkExt, StreamExt};
+    use tokio::net::TcpListener;
+    use tokio_tungstenite::{accept_async, connect_async_tls_with_config, Connector, tungstenite::Message};
+
+    #[tokio::test]
+    async fn wss() {
+        let certified = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let directory = std::env::temp_dir().join(format!("air_wss_{}", crate::names::Id::random()));
+        std::fs::create_dir(&directory).unwrap();
+        let tls = Tls{certificates: directory.join("cert.pem"), key: directory.join("key.pem")};
+
+        //A missing file or one without a key is an error instead of a panic
+        assert!(tls.acceptor().is_err());
+        std::fs::write(&tls.certificates, certified.cert.pem()).unwrap();
+        std::fs::write(&tls.key, certified.cert.pem()).unwrap();
+        assert!(tls.acceptor().is_err());
+        std::fs::write(&tls.key, certified.key_pair.serialize_pem()).unwrap();
+
+        let acceptor = tls.acceptor().unwrap();
+        let _ = std::fs::remove_dir_all(directory);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("wss://localhost:{}", listener.local_addr().unwrap().port());
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let Ok(stream) = acceptor.accept(stream).await else {continue};
+                let mut socket = accept_async(stream).await.unwrap();
+                socket.send(Message::Binary(b"hello".to_vec().into())).await.unwrap();
+            }
+        });
+
+        //The public roots do not know a self signed certificate
+        assert!(connect_async_tls_with_config(&url, None, false, Some(Connector::Rustls(client(&RootCertStore{roots: webpki_roots::TLS_SERVER_ROOTS.to_vec()})))).await.is_err());
+
+        let mut roots = RootCertStore::empty();
+        roots.add(certified.cert.der().clone()).unwrap();
+        let (mut socket, _) = connect_async_tls_with_config(&url, None, false, Some(Connector::Rustls(client(&roots)))).await.unwrap();
+        assert_eq!(socket.next().await.unwrap().unwrap(), Message::Binary(b"hello".to_vec().into()));
+    }
+}
@@ -0,0 +1,386 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex, LazyLock};
+
+use axum::extract::ws::{WebSocket, Message as WsMessage};
+use axum::http::{StatusCode, HeaderMap};
+use crossfire::{MAsyncTx, AsyncTx, AsyncRx, mpsc, spsc};
+use futures_util::{SinkExt, StreamExt};
+use rustls::RootCertStore;
+use std::net::SocketAddr;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::spawn;
+use tokio::time::{timeout, Duration};
+use tokio_socks::tcp::Socks5Stream;
+use tokio_tungstenite::{connect_async_tls_with_config, client_async_tls_with_config, Connector, WebSocketStream};
+use tokio_tungstenite::tungstenite::{self, Message, client::IntoClientRequest};
+use tungstenite::handshake::client::{Request, Response};
+
+use crate::names::{Clock, Id};
+use super::tls;
+
+///Header the server answers a handshake with
+pub const REPLY: &str = "X-Reply";
+///Urls of Chandlers in this process, reached through channels instead of sockets
+pub const LOOPBACK: &str = "loopback://";
+///How long a long poll waits for a frame before answering with none
+const POLL_WAIT: Duration = Duration::from_secs(25);
+///Long poll sessions without a request for this many nano seconds are closed
+pub const POLL_IDLE: u64 = 60_000_000_000;
+///Polls in a row that may fail to arrive before the session is given up on
+const POLL_RETRIES: u32 = 3;
+
+///Encrypted frames, each a postcard Message, to the other end of a websocket or long poll
+pub type FrameTx = MAsyncTx<mpsc::List<Vec<u8>>>;
+///Encrypted frames from the other end, closed along with the transport
+pub type FrameRx = AsyncRx<mpsc::List<Vec<u8>>>;
+
+///A handshake for an in process Chandler, its header, value and where to send the frames and reply header
+pub type Dial = (&'static str, String, AsyncTx<spsc::One<Option<(FrameTx, FrameRx, String)>>>);
+
+static LISTENERS: LazyLock<Mutex<HashMap<String, MAsyncTx<mpsc::List<Dial>>>>> = LazyLock::new(Mutex::default);
+
+///Serves loopback://{host} in this process
+pub fn listen(host: &str) -> AsyncRx<mpsc::List<Dial>> {
+    let (tx, rx) = mpsc::build(mpsc::List::new());
+    LISTENERS.lock().unwrap().insert(host.to_string(), tx);
+    rx
+}
+
+///Hands the handshake to the Chandler serving loopback://{host}
+pub async fn dial(host: &str, header: &'static str, handshake: String) -> Option<(FrameTx, FrameRx, String)> {
+    let listener = LISTENERS.lock().unwrap().get(host).cloned()?;
+    let (tx, rx) = spsc::build(spsc::One::new());
+    listener.send((header, handshake, tx)).await.ok()?;
+    rx.recv().await.ok()?
+}
+
+///Two ends of an in process connection, the first end sends what the second receives and the other way around
+pub fn pipe() -> ((FrameTx, FrameRx), (FrameTx, FrameRx)) {
+    let ((atx, brx), (btx, arx)) = (mpsc::build(mpsc::List::new()), mpsc::build(mpsc::List::new()));
+    ((atx, arx), (btx, brx))
+}
+
+///How the Purser reaches a Chandler, a websocket when the network allows the upgrade and HTTP long polling when not,
+///both through the SOCKS5 proxy when there is one
+#[derive(Debug, Clone)]
+pub struct Transport {
+    websocket: Connector,
+    http: reqwest::Client,
+    proxy: Option<SocketAddr>,
+}
+impl Transport {
+    pub fn new(roots: &RootCertStore, proxy: Option<SocketAddr>) -> Self {
+        let config = tls::client(roots);
+        let mut http = reqwest::Client::builder().use_preconfigured_tls((*config).clone());
+        if let Some(proxy) = proxy {
+            //socks5h so host names are resolved by the proxy and never leak through our DNS
+            http = http.proxy(reqwest::Proxy::all(format!("socks5h://{proxy}")).unwrap());
+        }
+        Transport{websocket: Connector::Rustls(config), http: http.build().unwrap(), proxy}
+    }
+
+    async fn upgrade(&self, request: Request) -> Result<((FrameTx, FrameRx), Response), tungstenite::Error> {
+        match self.proxy {
+            Some(proxy) => {
+                let uri = request.uri();
+                let host = uri.host().unwrap_or_default().to_string();
+                let port = uri.port_u16().unwrap_or(if uri.scheme_str() == Some("wss") {443} else {80});
+                let stream = Socks5Stream::connect(proxy, (host.as_str(), port)).await.map_err(|e| tungstenite::Error::Io(std::io::Error::other(e)))?;
+                let (socket, response) = client_async_tls_with_config(request, stream, None, Some(self.websocket.clone())).await?;
+                Ok((Self::websocket(socket), response))
+            },
+            None => {
+                let (socket, response) = connect_async_tls_with_config(request, None, false, Some(self.websocket.clone())).await?;
+                Ok((Self::websocket(socket), response))
+            }
+        }
+    }
+
+    ///Sends the handshake in the header, returning the frames and the servers reply header, empty when it sent none
+    pub async fn connect(&self, url: &str, header: &'static str, handshake: String) -> Option<(FrameTx, FrameRx, String)> {
+        if let Some(host) = url.strip_prefix(LOOPBACK) {return dial(host, header, handshake).await}
+        let mut request = url.into_client_request().unwrap();
+        request.headers_mut().insert(header, handshake.parse().unwrap());
+        match self.upgrade(request).await {
+            Ok(((tx, rx), response)) => Some((tx, rx, Self::reply(response.headers()))),
+            Err(e) => {
+                println!("Websocket Failed, Long Polling: {e}");
+                let poll = format!("{}/poll", url.trim_end_matches('/').replacen("ws", "http", 1));
+                let response = self.http.post(&poll).header(header, handshake).send().await.ok()?.error_for_status().ok()?;
+                let reply = Self::reply(response.headers());
+                let session = format!("{poll}/{}", response.text().await.ok()?);
+                let (tx, rx) = self.long_poll(session);
+                Some((tx, rx, reply))
+            }
+        }
+    }
+
+    fn reply(headers: &HeaderMap) -> String {
+        headers.get(REPLY).and_then(|reply| reply.to_str().ok()).unwrap_or_default().to_string()
+    }
+
+    fn websocket<T: AsyncRead + AsyncWrite + Unpin + Send + 'static>(socket: WebSocketStream<T>) -> (FrameTx, FrameRx) {
+        let (mut write, mut read) = socket.split();
+        let ((otx, orx), (itx, irx)) = (mpsc::build(mpsc::List::new()), mpsc::build(mpsc::List::new()));
+        spawn(async move {
+            while let Ok(frame) = orx.recv().await {
+                if write.send(Message::Binary(frame.into())).await.is_err() {break}
+            }
+            //Every sender is gone, closing lets the server end the session and our read task see the close
+            let _ = write.close().await;
+        });
+        spawn(async move {
+            while let Some(Ok(message)) = read.next().await {match message {
+                Message::Binary(payload) => if itx.send(payload.to_vec()).await.is_err() {break},
+                Message::Close(_) => break,
+                m => println!("Unexpected Message: {m:?}")
+            }}
+        });
+        (otx, irx)
+    }
+
+    ///Frames are posted one at a time, in order, while a single long poll is always waiting for responses. Each
+    ///poll carries how many frames have arrived so a poll whose response was lost is answered again
+    fn long_poll(&self, session: String) -> (FrameTx, FrameRx) {
+        let ((otx, orx), (itx, irx)) = (mpsc::build(mpsc::List::new()), mpsc::build(mpsc::List::new()));
+        let (http, url) = (self.http.clone(), session.clone());
+        spawn(async move {
+            while let Ok(frame) = orx.recv().await {
+                if http.post(&url).body(frame).send().await.and_then(|r| r.error_for_status()).is_err() {break}
+            }
+        });
+        let http = self.http.clone();
+        spawn(async move {
+            let (mut received, mut failures) = (0u64, 0);
+            //Stops polling once nothing reads the frames, the server closes the session after POLL_IDLE
+            while !itx.is_disconnected() && failures < POLL_RETRIES {
+                let response = http.get(format!("{session}?after={received}")).send().await.and_then(|r| r.error_for_status());
+                let frames = match response {
+                    Ok(response) => response.bytes().await.ok().and_then(|body| postcard::from_bytes::<Vec<Vec<u8>>>(&body).ok()),
+                    Err(e) if e.status().is_some() => break,
+                    Err(_) => None
+                };
+                let Some(frames) = frames else {failures += 1; continue};
+                failures = 0;
+                received += frames.len() as u64;
+                for frame in frames {
+                    if itx.send(frame).await.is_err() {return}
+                }
+            }
+        });
+        (otx, irx)
+    }
+}
+
+///Pumps an accepted websocket into the frames the Chandler reads requests from and writes responses to
+pub fn accept(socket: WebSocket) -> (FrameRx, FrameTx) {
+    let (mut write, mut read) = socket.split();
+    let ((otx, orx), (itx, irx)) = (mpsc::build(mpsc::List::new()), mpsc::build(mpsc::List::new()));
+    spawn(async move {
+        while let Ok(frame) = orx.recv().await {
+            if write.send(WsMessage::Binary(frame.into())).await.is_err() {break}
+        }
+    });
+    spawn(async move {
+        while let Some(Ok(message)) = read.next().await {match message {
+            WsMessage::Binary(payload) => if itx.send(payload.to_vec()).await.is_err() {break},
+            WsMessage::Close(_) => break,
+            m => println!("Ignored Request: {m:?}")
+        }}
+    });
+    (irx, otx)
+}
+
+///Frames for the client, kept until a later poll shows they arrived
+struct Outgoing {
+    frames: FrameRx,
+    unconfirmed: VecDeque<Vec<u8>>,
+    ///How many frames came before the first unconfirmed one
+    confirmed: u64,
+}
+
+struct Poll {
+    incoming: FrameTx,
+    outgoing: Arc<tokio::sync::Mutex<Outgoing>>,
+    seen: u64,
+}
+
+///Long poll sessions by the token their client was given, a session closes once it is idle for POLL_IDLE on
+///the Chandlers clock
+#[derive(Clone)]
+pub struct Polls(Arc<Mutex<HashMap<Id, Poll>>>, Arc<dyn Clock>);
+impl Polls {
+    pub fn new(clock: Arc<dyn Clock>) -> Self {Polls(Arc::default(), clock)}
+
+    ///Returns the token along with the frames the Chandler reads requests from and writes responses to
+    pub fn open(&self) -> (Id, FrameRx, FrameTx) {
+        let ((otx, orx), (itx, irx)) = (mpsc::build(mpsc::List::new()), mpsc::build(mpsc::List::new()));
+        let token = Id::random();
+        let outgoing = Outgoing{frames: orx, unconfirmed: VecDeque::new(), confirmed: 0};
+        self.0.lock().unwrap().insert(token, Poll{incoming: itx, outgoing: Arc::new(tokio::sync::Mutex::new(outgoing)), seen: self.1.now()});
+        (token, irx, otx)
+    }
+
+    fn get(&self, token: &Id) -> Option<(FrameTx, Arc<tokio::sync::Mutex<Outgoing>>)> {
+        let mut polls = self.0.lock().unwrap();
+        let poll = polls.get_mut(token)?;
+        poll.seen = self.1.now();
+        Some((poll.incoming.clone(), poll.outgoing.clone()))
+    }
+
+    pub async fn push(&self, token: Id, frame: Vec<u8>) -> StatusCode {
+        let Some((incoming, _)) = self.get(&token) else {return StatusCode::NOT_FOUND};
+        match incoming.send(frame).await {
+            Ok(()) => StatusCode::ACCEPTED,
+            Err(_) => StatusCode::GONE
+        }
+    }
+
+    ///Every frame after the first `after` the client has received, waiting up to POLL_WAIT for one if there
+    ///are none. Frames before it are confirmed and dropped, the rest are sent again until they are
+    pub async fn poll(&self, token: Id, after: u64) -> Result<Vec<Vec<u8>>, StatusCode> {
+        let (_, outgoing) = self.get(&token).ok_or(StatusCode::NOT_FOUND)?;
+        let mut outgoing = outgoing.lock().await;
+        let confirmed = after.checked_sub(outgoing.confirmed).filter(|c| *c as usize <= outgoing.unconfirmed.len()).ok_or(StatusCode::BAD_REQUEST)?;
+        outgoing.unconfirmed.drain(..confirmed as usize);
+        outgoing.confirmed = after;
+        if outgoing.unconfirmed.is_empty() {
+            match timeout(POLL_WAIT, outgoing.frames.recv()).await {
+                Ok(Ok(frame)) => outgoing.unconfirmed.push_back(frame),
+                Ok(Err(_)) => Err(StatusCode::GONE)?,
+                Err(_) => {}
+            }
+        }
+        while let Ok(frame) = outgoing.frames.try_recv() {outgoing.unconfirmed.push_back(frame);}
+        Ok(outgoing.unconfirmed.iter().cloned().collect())
+    }
+
+    pub fn clean(&self) {
+        let time = self.1.now();
+        self.0.lock().unwrap().retain(|_, poll| poll.seen + POLL_IDLE > time);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt, copy_bidirectional};
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio_tungstenite::accept_hdr_async;
+    use tungstenite::handshake::server::{Request, Response, ErrorResponse};
+
+    ///Just enough of SOCKS5 to CONNECT without authentication, records every target it is asked for
+    async fn socks5(listener: TcpListener, targets: Arc<Mutex<Vec<String>>>) {
+        while let Ok((mut client, _)) = listener.accept().await {
+            let targets = targets.clone();
+            spawn(async move {
+                let mut greeting = [0u8; 2];
+                client.read_exact(&mut greeting).await.unwrap();
+                client.read_exact(&mut vec![0u8; greeting[1] as usize]).await.unwrap();
+                client.write_all(&[5, 0]).await.unwrap();
+                let mut head = [0u8; 4];
+                client.read_exact(&mut head).await.unwrap();
+                let host = match head[3] {
+                    1 => {
+                        let mut ip = [0u8; 4];
+                        client.read_exact(&mut ip).await.unwrap();
+                        std::net::Ipv4Addr::from(ip).to_string()
+                    },
+                    3 => {
+                        let mut name = vec![0u8; client.read_u8().await.unwrap() as usize];
+                        client.read_exact(&mut name).await.unwrap();
+                        String::from_utf8(name).unwrap()
+                    },
+                    a => panic!("Unsupported address type {a}")
+                };
+                let target = format!("{host}:{}", client.read_u16().await.unwrap());
+                targets.lock().unwrap().push(target.clone());
+                let mut server = TcpStream::connect(target).await.unwrap();
+                client.write_all(&[5, 0, 0, 1, 0, 0, 0, 0, 0, 0]).await.unwrap();
+                let _ = copy_bidirectional(&mut client, &mut server).await;
+            });
+        }
+    }
+
+    #[tokio::test]
+    #[allow(clippy::result_large_err)]
+    async fn socks() {
+        let server = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://localhost:{}", server.local_addr().unwrap().port());
+        spawn(async move {
+            let (stream, _) = server.accept().await.unwrap();
+            let mut socket = accept_hdr_async(stream, |_: &Request, mut response: Response| -> Result<Response, ErrorResponse> {
+                response.headers_mut().insert(REPLY, "00".parse().unwrap());
+                Ok(response)
+            }).await.unwrap();
+            let echo = socket.next().await.unwrap().unwrap();
+            socket.send(echo).await.unwrap();
+        });
+
+        let proxy = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let (address, targets) = (proxy.local_addr().unwrap(), Arc::new(Mutex::new(vec![])));
+        spawn(socks5(proxy, targets.clone()));
+
+        let transport = Transport::new(&RootCertStore::empty(), Some(address));
+        let (tx, rx, reply) = transport.connect(&url, "X-Public-Key", "00".to_string()).await.unwrap();
+        assert_eq!(reply, "00");
+        tx.send(b"hello".to_vec()).await.unwrap();
+        assert_eq!(rx.recv().await.unwrap(), b"hello".to_vec());
+        //The proxy resolved the host, not us
+        assert_eq!(*targets.lock().unwrap(), vec![url.trim_start_matches("ws://").to_string()]);
+    }
+
+    #[tokio::test]
+    async fn poll() {
+        let polls = Polls::new(Arc::new(crate::names::SystemClock));
+        let (token, _incoming, outgoing) = polls.open();
+        outgoing.send(b"one".to_vec()).await.unwrap();
+        assert_eq!(polls.poll(token, 0).await, Ok(vec![b"one".to_vec()]));
+        //The response was lost, asking from the same place answers it again along with anything new
+        outgoing.send(b"two".to_vec()).await.unwrap();
+        assert_eq!(polls.poll(token, 0).await, Ok(vec![b"one".to_vec(), b"two".to_vec()]));
+        outgoing.send(b"three".to_vec()).await.unwrap();
+        assert_eq!(polls.poll(token, 2).await, Ok(vec![b"three".to_vec()]));
+        //Confirmed frames are gone and the client cannot claim frames it was never sent
+        assert_eq!(polls.poll(token, 1).await, Err(StatusCode::BAD_REQUEST));
+        assert_eq!(polls.poll(token, 4).await, Err(StatusCode::BAD_REQUEST));
+        drop(outgoing);
+        assert_eq!(polls.poll(token, 3).await, Err(StatusCode::GONE));
+    }
+
+    #[tokio::test]
+    async fn idle() {
+        let clock = Arc::new(crate::names::ManualClock::new(0));
+        let polls = Polls::new(clock.clone());
+        let (token, _incoming, _outgoing) = polls.open();
+        clock.advance(POLL_IDLE - 1);
+        polls.clean();
+        assert_eq!(polls.push(token, vec![]).await, StatusCode::ACCEPTED);
+        //The push was seen, so the session is idle from then on
+        clock.advance(POLL_IDLE);
+        polls.clean();
+        assert_eq!(polls.push(token, vec![]).await, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    #[allow(clippy::result_large_err)]
+    async fn close() {
+        let server = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", server.local_addr().unwrap());
+        let closed = spawn(async move {
+            let (stream, _) = server.accept().await.unwrap();
+            let mut socket = accept_hdr_async(stream, |_: &Request, mut response: Response| -> Result<Response, ErrorResponse> {
+                response.headers_mut().insert(REPLY, "00".parse().unwrap());
+                Ok(response)
+            }).await.unwrap();
+            matches!(socket.next().await, None | Some(Ok(Message::Close(_))))
+        });
+
+        let transport = Transport::new(&RootCertStore::empty(), None);
+        let (tx, rx, _) = transport.connect(&url, "X-Public-Key", "00".to_string()).await.unwrap();
+        drop(tx);
+        assert!(timeout(Duration::from_secs(5), closed).await.unwrap().unwrap());
+        //The servers close answers ours and ends the frames from it
+        assert!(timeout(Duration::from_secs(5), rx.recv()).await.unwrap().is_err());
+    }
+}
@@ -44,7 +44,7 @@ impl Services {
     }
 }
 
-use crate::{Metadata, Reactant, Contract, Reactants, Instance, names::now};
+use crate::{Metadata, Reactant, Contract, Reactants, Instance};
 use serde::{Serialize, Deserialize};
 use tokio::time::{sleep, Sleep, Duration};
 
@@ -59,7 +59,7 @@ impl<S: Service> Lock<S> {
             println!("applying obtain");
             match instance.try_apply(Obtain(my_id)).confirmed().await {
                 Ok(time) => {
-                    *remaining = Some(Box::pin(sleep(Duration::from_nanos((time+LOCK).saturating_sub(now()+MARGIN)))));
+                    *remaining = Some(Box::pin(sleep(Duration::from_nanos((time+LOCK).saturating_sub(instance.now()+MARGIN)))));
                     break
                 },
                 Err(wait) => {
@@ -157,3 +157,94 @@ impl Reactant<ServiceLock> for Release {
         } else {Err(lock.0)}
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{Air, Clock, ManualClock, Secret, PendingResult};
+
+    #[test]
+    fn expiry() {
+        use crate::server::loopback_server;
+        let (server, secret) = (Secret::new(), Secret::new());
+        //Even, so the offset estimated between the two identical clocks comes out at exactly zero
+        let clock = std::sync::Arc::new(ManualClock::new(crate::names::now() & !1));
+        let resolver = crate::names::Resolver::start();
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(loopback_server(&server, &resolver, clock.clone()));
+
+        //Two devices of the same user, on the servers clock
+        let config = crate::Config{server: server.name(), clock: clock.clone(), resolver, ..Default::default()};
+        let (first, ours) = Air::start_with(secret.clone(), Services::default(), config.clone());
+        let (_second, theirs) = Air::start_with(secret, Services::default(), config);
+        let (a, b) = (Id::random(), Id::random());
+        first.handle.block_on(async {
+            let mut ours = ours.create::<ServiceLock>(Probe::id());
+            let mut held = None;
+            assert!(!Lock::<Probe>::obtain(&mut ours, a, &mut held).await);
+            assert_eq!((ours.load_confirmed().0, ours.load_confirmed().1), (a, clock.now()));
+            assert!(held.as_ref().is_some_and(|h| !h.is_elapsed()));
+
+            //Half way through the other device is told how long is left
+            clock.advance(LOCK / 2);
+            let mut theirs = theirs.create::<ServiceLock>(Probe::id());
+            theirs.head().await;
+            assert!(matches!(theirs.try_apply(Obtain(b)), PendingResult::Err(wait) if wait == LOCK / 2));
+
+            //Once it runs out the other device takes it, stamped by the server
+            clock.advance(LOCK / 2 + 2);
+            assert!(!Lock::<Probe>::obtain(&mut theirs, b, &mut None).await);
+            assert_eq!((theirs.load_confirmed().0, theirs.load_confirmed().1), (b, clock.now()));
+            while ours.load_confirmed().0 != b {ours.listen_confirmed().await;}
+            assert!(matches!(ours.try_apply(Obtain(a)), PendingResult::Err(wait) if wait == LOCK));
+            assert_eq!(*ours.apply(Release(a)).confirmed().await, Err(b));
+        });
+    }
+
+    static STARTED: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+
+    ///Counts every start, which only happens once the lock around it is obtained
+    struct Probe;
+    impl Service for Probe {
+        fn id() -> Id {Id::hash("Probe")}
+        async fn new(_ctx: &mut Context, _secret: Secret) -> Self {
+            STARTED.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            Probe
+        }
+        async fn run(&mut self, _ctx: &mut Context) {std::future::pending().await}
+        async fn shutdown(self, _ctx: &mut Context) {}
+    }
+
+    #[test]
+    fn partition() {
+        use crate::server::{loopback_server, simulator::{Network, Faults}};
+        let (server, secret) = (Secret::new(), Secret::new());
+        let name = server.name().to_string();
+        let clock = std::sync::Arc::new(ManualClock::new(crate::names::now()));
+        let network = Network::new(3, Faults{drop: 0.05, duplicate: 0.05, reorder: 0.05, delay: 20});
+        let resolver = crate::names::Resolver::start();
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            loopback_server(&server, &resolver, clock.clone()).await;
+            resolver.publish(&server, crate::names::URL, network.route(&name)).await;
+        });
+        let started = |count| runtime.block_on(async {
+            while STARTED.load(std::sync::atomic::Ordering::SeqCst) < count {sleep(Duration::from_millis(10)).await;}
+        });
+
+        //Two devices of the same user contend for the lock
+        let config = crate::Config{server: server.name(), clock: clock.clone(), resolver, ..Default::default()};
+        let _first = Air::start_with(secret.clone(), Services::default().add::<Lock<Probe>>(), config.clone());
+        started(1);
+
+        //The holders lock runs out while the server is unreachable, the other device still waits on it
+        network.partition(&name);
+        let _second = Air::start_with(secret, Services::default().add::<Lock<Probe>>(), config);
+        clock.advance(2 * LOCK);
+        std::thread::sleep(Duration::from_secs(2));
+        assert_eq!(STARTED.load(std::sync::atomic::Ordering::SeqCst), 1);
+
+        network.heal(&name);
+        started(2);
+    }
+}
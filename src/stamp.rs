@@ -0,0 +1,148 @@
+use serde::{Serialize, Deserialize};
+
+use crate::names::{Name, Id, Secret, Signed, Resolver, Identity};
+use crate::storage::Mailbox;
+
+///Leading zero bits required when the recipient has not published a difficulty
+pub const DEFAULT_DIFFICULTY: u32 = 8;
+///A difficulty no stamp can meet, only allowlisted senders can deliver
+pub const CLOSED: u32 = 257;
+///Most leading zero bits a sender will work for, anything above is treated as closed to us
+pub const MAX_DIFFICULTY: u32 = 28;
+///How far, in nano seconds, a stamps timestamp may be from the servers clock
+pub const WINDOW: u64 = 600_000_000_000;//10 minutes
+
+///What a mailbox admits, the recipient stores it in the slot at the mailbox key when opening the mailbox
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Policy {
+    pub difficulty: u32,
+    pub allowlist: Vec<Name>,
+    ///Lets a session authenticated as this Name receive and ack without the mailbox key, at the cost of
+    ///linking the mailbox to the Name for the server
+    pub owner: Option<Name>,
+}
+impl Policy {
+    pub fn of(identity: &Identity) -> Self {
+        Policy{difficulty: identity.inbox_difficulty(), allowlist: identity.inbox_allowlist(), owner: None}
+    }
+}
+impl Default for Policy {fn default() -> Self {Policy{difficulty: DEFAULT_DIFFICULTY, allowlist: vec![], owner: None}}}
+
+///Admission for a missive, bound to its mailbox, timestamp and payload hash so it cannot be reused elsewhere
+#[derive(Serialize, Deserialize, Debug, Clone, Hash, PartialEq, Eq)]
+pub enum Stamp {
+    ///Timestamp and nonce whose hash meets the mailboxes difficulty
+    Work(u64, u64),
+    ///The senders signature over the mailbox, timestamp and hash, accepted if the sender is allowlisted
+    Allowed(Signed<(Mailbox, u64, Id)>),
+}
+
+impl Stamp {
+    ///Picks the cheapest stamp the policy accepts from this sender, Err if it would have to work past MAX_DIFFICULTY
+    pub async fn new(sender: &Secret, mailbox: Mailbox, policy: &Policy, payload: &[u8], timestamp: u64) -> Result<Self, String> {
+        if policy.allowlist.contains(&sender.name()) {
+            Ok(Self::allowed(sender, mailbox, payload, timestamp))
+        } else {
+            Self::mine(mailbox, policy, payload, timestamp).await
+        }
+    }
+
+    ///An Allowed stamp names its sender to the server, sealed senders do the work instead unless the
+    ///recipient only accepts allowlisted senders
+    pub async fn sealed(sender: &Secret, mailbox: Mailbox, policy: &Policy, payload: &[u8], timestamp: u64) -> Result<Self, String> {
+        if policy.difficulty > MAX_DIFFICULTY {
+            Self::new(sender, mailbox, policy, payload, timestamp).await
+        } else {
+            Self::mine(mailbox, policy, payload, timestamp).await
+        }
+    }
+
+    async fn mine(mailbox: Mailbox, policy: &Policy, payload: &[u8], timestamp: u64) -> Result<Self, String> {
+        let (hash, difficulty) = (Id::hash(payload), policy.difficulty);
+        tokio::task::spawn_blocking(move || Self::work(mailbox, hash, difficulty, timestamp)).await.unwrap()
+    }
+
+    ///Refuses a closed mailbox or one asking for more than MAX_DIFFICULTY rather than working forever
+    pub fn work(mailbox: Mailbox, hash: Id, difficulty: u32, timestamp: u64) -> Result<Self, String> {
+        if difficulty >= CLOSED {Err("Mailbox Closed".to_string())?}
+        if difficulty > MAX_DIFFICULTY {Err("Difficulty Too High".to_string())?}
+        let nonce = (0..u64::MAX).find(|nonce| Self::zeros(Id::hash(&(mailbox, timestamp, hash, nonce))) >= difficulty);
+        Ok(Self::Work(timestamp, nonce.ok_or("Difficulty Too High".to_string())?))
+    }
+
+    pub fn allowed(sender: &Secret, mailbox: Mailbox, payload: &[u8], timestamp: u64) -> Self {
+        Self::Allowed(Signed::new(sender, (mailbox, timestamp, Id::hash(payload))))
+    }
+
+    pub fn timestamp(&self) -> u64 {match self {
+        Self::Work(timestamp, _) => *timestamp,
+        Self::Allowed(signed) => signed.payload.1
+    }}
+
+    ///Checks the stamp against the mailboxes policy at the servers time, returning an id unique to the stamp for replay tracking
+    pub async fn verify(&self, resolver: &Resolver, mailbox: Mailbox, policy: &Policy, payload: &[u8], now: u64) -> Result<Id, String> {
+        let hash = Id::hash(payload);
+        let timestamp = self.timestamp();
+        if now.abs_diff(timestamp) > WINDOW {Err("Stamp Expired".to_string())?}
+        match self {
+            Self::Work(timestamp, nonce) => {
+                if Self::zeros(Id::hash(&(mailbox, timestamp, hash, nonce))) < policy.difficulty {
+                    Err("Insufficient Work".to_string())?
+                }
+            },
+            Self::Allowed(signed) => {
+                if signed.payload != (mailbox, timestamp, hash) {Err("Stamp Mismatch".to_string())?}
+                if !policy.allowlist.contains(&signed.signer) {Err("Sender Not Allowed".to_string())?}
+                signed.verify(&resolver.resolve(signed.signer, Some(timestamp)).await, &[]).map_err(|e| e.to_string())?;
+            }
+        }
+        Ok(Id::hash(&(mailbox, timestamp, hash)))
+    }
+
+    fn zeros(id: Id) -> u32 {
+        let mut zeros = 0;
+        for byte in id.iter() {
+            zeros += byte.leading_zeros();
+            if *byte != 0 {break}
+        }
+        zeros
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::names::{now, secp256k1::SecretKey};
+
+    #[tokio::test]
+    async fn stamps() {
+        let resolver = Resolver::start();
+        let (alice, bob) = (Secret::new(), Secret::new());
+        let mailbox = SecretKey::new().public_key();
+        let payload = b"location".to_vec();
+
+        resolver.publish(&bob, crate::names::INBOX_DIFFICULTY, "4".to_string()).await;
+        let policy = Policy::of(&resolver.resolve(bob.name(), None).await);
+        let stamp = Stamp::new(&alice, mailbox, &policy, &payload, now()).await.unwrap();
+        assert!(matches!(stamp, Stamp::Work(..)));
+        stamp.verify(&resolver, mailbox, &policy, &payload, now()).await.unwrap();
+        assert!(stamp.verify(&resolver, mailbox, &policy, b"other", now()).await.is_err());
+        assert!(stamp.verify(&resolver, SecretKey::new().public_key(), &policy, &payload, now()).await.is_err());
+        assert!(stamp.verify(&resolver, mailbox, &policy, &payload, stamp.timestamp() + WINDOW + 1).await.is_err());
+
+        resolver.publish(&bob, crate::names::INBOX_DIFFICULTY, CLOSED.to_string()).await;
+        resolver.publish(&bob, crate::names::INBOX_ALLOWLIST, alice.name().to_string()).await;
+        let policy = Policy::of(&resolver.resolve(bob.name(), None).await);
+        let stamp = Stamp::new(&alice, mailbox, &policy, &payload, now()).await.unwrap();
+        assert!(matches!(stamp, Stamp::Allowed(_)));
+        stamp.verify(&resolver, mailbox, &policy, &payload, now()).await.unwrap();
+        assert!(matches!(Stamp::sealed(&alice, mailbox, &policy, &payload, now()).await, Ok(Stamp::Allowed(_))));
+        assert!(Stamp::work(mailbox, Id::hash(&payload), 4, now()).unwrap().verify(&resolver, mailbox, &policy, &payload, now()).await.is_err());
+
+        //Not allowlisted, a closed or too demanding mailbox is refused instead of worked on
+        let carol = Secret::new();
+        assert_eq!(Stamp::sealed(&carol, mailbox, &policy, &payload, now()).await, Err("Mailbox Closed".to_string()));
+        let expensive = Policy{difficulty: MAX_DIFFICULTY + 1, ..Policy::default()};
+        assert_eq!(Stamp::sealed(&carol, mailbox, &expensive, &payload, now()).await, Err("Difficulty Too High".to_string()));
+    }
+}
@@ -1,40 +1,92 @@
 use std::collections::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard};
 use std::hash::Hash;
 use std::fmt::Debug;
 
-use crate::names::{now, Name, Signature, Id, Secret, Signed, Resolver};
+use crate::names::{Clock, Name, Signature, Id, Secret, Signed, Resolver, Ticket};
+use crate::stamp::{Stamp, Policy, WINDOW};
 use crate::names::secp256k1::{Signature as KeySignature, Signed as KeySigned, PublicKey};
 
 use serde::{Serialize, Deserialize};
-use rusqlite::{Connection, params, OptionalExtension};
 
-use crossfire::{MAsyncTx, AsyncTx, AsyncRx, mpsc, spsc};
+use crossfire::{AsyncTx, AsyncRx, spsc};
 use tokio::spawn;
+use tokio::task::spawn_blocking;
+use tokio::time::{interval, Duration};
 
-pub type Time = (Compare, u64);
+mod sqlite;
+pub use sqlite::Sqlite;
+mod memory;
+pub use memory::Memory;
+mod sharded;
+pub use sharded::Sharded;
 
-#[derive(Serialize, Deserialize, Debug, Clone, Hash, PartialEq, Eq, Copy)]
-pub enum Compare {Greater, GreaterOrEqual, Equal, LesserOrEqual, Lesser}
-impl std::fmt::Display for Compare {fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {write!(f, "{}", match self {
-    Self::Greater => ">".to_string(),
-    Self::GreaterOrEqual => ">=".to_string(),
-    Self::Equal => "=".to_string(),
-    Self::LesserOrEqual => "<=".to_string(),
-    Self::Lesser => "<".to_string(),
-})}}
+///Key a recipient receives missives under, derived from their Secret and rotated so the server cannot link it to their Name
+pub type Mailbox = PublicKey;
+///Position of a missive within its inbox, assigned by the server in increasing order
+pub type Cursor = u64;
+///Most missives a single Response::Inbox will carry
+pub const MAX_PAGE: u32 = 256;
+///How often expired missives are deleted
+pub const EXPIRE_INTERVAL: u64 = 3_600_000_000_000;//1 hour
+///Signed over in place of a payload hash when an index has been pruned
+pub const PRUNED: &str = "PRUNED";
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Hash, PartialEq, Eq)]
+pub struct Page {
+    pub after: Cursor,
+    pub limit: u32
+}
+
+///Who a mailbox request is made by, either signed by the mailbox key or sent over a session authenticated as the
+///Name its Policy names as owner
+#[derive(Serialize, Deserialize, Debug, Clone, Hash, PartialEq, Eq)]
+pub enum Authorized<T: Hash + Debug> {
+    Signed(KeySigned<T>),
+    Session(Mailbox, T),
+}
 
 #[derive(Serialize, Deserialize, Debug, Clone, Hash, PartialEq, Eq)]
 pub enum Request{
     Create(KeySigned<Vec<u8>>),
     Read(PublicKey, bool),//Subscribe
+    ///Reads up to MAX_PAGE slots in one round trip, never subscribes
+    ReadMany(Vec<PublicKey>),
 
-    Send(Name, Vec<u8>),
-    Receive(Signed<Time>),
+    ///Appends to the log at the signing key, the server picks the next index
+    Log(KeySigned<Vec<u8>>),
+    ///Reads the entry at an index of a log, subscribing waits for it to be appended
+    Entry(PublicKey, u64, bool),//Subscribe
+    ///Deletes the entries below the index from the signing log. The slot at the key is the checkpoint that
+    ///covers them, its payload has to start with the postcard log key and the position it was taken at
+    Prune(KeySigned<(u64, PublicKey)>),
+
+    ///Accepted once the mailbox has been opened by creating the slot at its key with a postcard Policy
+    Send(Mailbox, Vec<u8>, Stamp),
+    Receive(Authorized<Page>),
+    ///Deletes every missive up to and including the cursor from the mailbox
+    Ack(Authorized<Cursor>),
+
+    ///Signs the connections session id with the Secret at the given path, the connection then acts as that Name.
+    ///Handled by the Chandler, never reaches storage
+    Authenticate(Signed<Id>, Vec<Id>),
+    ///Asks for a Ticket to resume this session on the next connect, handled by the Chandler
+    Ticket,
 }
 
 impl Request {
+    ///Payload bytes the request asks the server to store
+    pub fn size(&self) -> usize {match self {
+        Self::Create(signed) | Self::Log(signed) => signed.payload.len(),
+        Self::Send(_, payload, _) => payload.len(),
+        _ => 0
+    }}
+
+    ///Responses buffered before the sender waits on the receiver, a stream may send any number
     pub fn max_responses(&self) -> usize {match self {
         Self::Read(_, true) => 2,
+        Self::Entry(_, _, true) => 2,
+        Self::Receive(_) => 2,
         _ => 1
     }}
 }
@@ -43,157 +95,380 @@ impl Request {
 pub enum Response {
     Create(Signature, u64),
     Read(Signature, u64, Option<(KeySignature, Vec<u8>)>),
+    ///A Read for each key, in the order they were asked for
+    ReadMany(Vec<(Signature, u64, Option<(KeySignature, Vec<u8>)>)>),
+    ///Index and the servers signature and timestamp over the appended entry
+    Logged(u64, Signature, u64),
+    ///An empty index is signed over Id::MIN in place of the payload hash
+    Entry(u64, Signature, u64, Option<(KeySignature, Vec<u8>)>),
+    ///Every index below this one was pruned, signed over PRUNED in place of the payload hash
+    Pruned(u64, Signature, u64),
     
-    Inbox(Vec<(Signature, u64, Vec<u8>)>),
+    ///A page of missives, the flag is set when another page follows
+    Inbox(Vec<(Cursor, Missive)>, bool),
+    Ack(Cursor),
 
     InvalidRequest(String),
     InvalidSignature(String),
+    Authenticated(Name),
+    Ticket(Ticket),
+    ///The client is over one of the servers limits and should wait this many nano seconds before retrying
+    Throttled(u64),
+    ///The request was sent again after the responses the server kept for it were evicted, it was not run again
+    Replayed,
+}
+
+impl Response {
+    ///The servers time when it answered, filled slots carry the time they were filled instead
+    pub fn timestamp(&self) -> Option<u64> {match self {
+        Response::Create(_, timestamp) | Response::Read(_, timestamp, None) => Some(*timestamp),
+        Response::Logged(_, _, timestamp) | Response::Entry(_, _, timestamp, None) => Some(*timestamp),
+        Response::Pruned(_, _, timestamp) => Some(*timestamp),
+        _ => None
+    }}
+}
+
+pub type Responder = AsyncTx<spsc::Array<Response>>;
+
+///The servers signature and timestamp over a slot followed by the key holders signature and payload
+pub type Slot = (Signature, u64, KeySignature, Vec<u8>);
+///The servers signature over the log, index, timestamp and payload hash, its timestamp, then the key holders
+///signature and payload
+pub type Entry = (Signature, u64, KeySignature, Vec<u8>);
+///Builds the entry once the backend has picked its index
+pub type Sign = Box<dyn FnOnce(u64) -> Entry + Send>;
+///The servers signature and timestamp over a missive followed by its payload
+pub type Missive = (Signature, u64, Vec<u8>);
+
+///Where a Chandler keeps its slots, inboxes and the requests waiting on them.
+///Methods are called from many blocking tasks at once so implementations handle their own locking
+pub trait StorageBackend: Send + Sync + 'static {
+    ///Stores the slot if the key is empty and returns whatever occupies the key afterwards
+    fn create(&self, key: PublicKey, slot: Slot) -> Slot;
+    fn read(&self, key: &PublicKey) -> Option<Slot>;
+    fn read_many(&self, keys: &[PublicKey]) -> Vec<Option<Slot>> {keys.iter().map(|key| self.read(key)).collect()}
+
+    ///Stores the entry at the next index of the log, one past the last, and returns the index with the entry
+    fn log(&self, log: PublicKey, sign: Sign) -> (u64, Entry);
+    ///Returns the entry at the index, or the index the log has been pruned to if the entry is below it, both
+    ///read from the same snapshot so a prune can never land between them
+    fn entry(&self, log: &PublicKey, index: u64) -> Result<Option<Entry>, u64>;
+    ///Deletes the entries below the index, never past the end of the log, and returns the index everything
+    ///below has been pruned to. Later entries keep counting up from where the log was
+    fn prune(&self, log: PublicKey, below: u64) -> u64;
+
+    fn append(&self, recipient: Mailbox, missive: Missive) -> Cursor;
+    ///Returns up to limit missives with a cursor greater than after, in cursor order
+    fn query(&self, recipient: &Mailbox, after: Cursor, limit: usize) -> Vec<(Cursor, Missive)>;
+    ///Deletes the mailboxes missives up to and including the cursor
+    fn ack(&self, recipient: &Mailbox, cursor: Cursor);
+    ///Deletes every missive stored before the timestamp, acknowledged or not
+    fn expire(&self, before: u64);
+
+    fn subscriptions(&self) -> &Subscriptions;
 }
 
-type Responder = AsyncTx<spsc::Array<Response>>;
+pub(crate) fn shard<H: Hash + ?Sized>(key: &H, shards: usize) -> usize {
+    let id = Id::hash(key);
+    u64::from_le_bytes(id[0..8].try_into().unwrap()) as usize % shards
+}
 
+type Subscribers<K> = Vec<Mutex<HashMap<K, Vec<Responder>>>>;
+
+///Requests waiting for a slot or inbox to be filled, sharded by key so fan-out does not serialize on one lock.
+///Holding a shard while reading the backend and subscribing guarantees a concurrent write cannot be missed
+pub struct Subscriptions {
+    slots: Subscribers<PublicKey>,
+    logs: Subscribers<(PublicKey, u64)>,
+    inbox: Subscribers<Mailbox>,
+}
+impl Default for Subscriptions {fn default() -> Self {
+    let shards = std::thread::available_parallelism().map(|p| p.get()).unwrap_or(1) * 4;
+    Subscriptions{
+        slots: (0..shards).map(|_| Mutex::default()).collect(),
+        logs: (0..shards).map(|_| Mutex::default()).collect(),
+        inbox: (0..shards).map(|_| Mutex::default()).collect()
+    }
+}}
+impl Subscriptions {
+    pub fn slot(&self, key: &PublicKey) -> MutexGuard<'_, HashMap<PublicKey, Vec<Responder>>> {
+        self.slots[shard(key, self.slots.len())].lock().unwrap()
+    }
+    pub fn log(&self, log: &PublicKey, index: u64) -> MutexGuard<'_, HashMap<(PublicKey, u64), Vec<Responder>>> {
+        self.logs[shard(&(log, index), self.logs.len())].lock().unwrap()
+    }
+    pub fn inbox(&self, recipient: &Mailbox) -> MutexGuard<'_, HashMap<Mailbox, Vec<Responder>>> {
+        self.inbox[shard(recipient, self.inbox.len())].lock().unwrap()
+    }
+}
+
+///Every request is handled on its own task, backend calls run on the blocking pool so reads
+///proceed while writes are being committed
 #[derive(Clone)]
-pub struct Storage(MAsyncTx<mpsc::List<(Request, Responder)>>);
+#[allow(clippy::type_complexity)]
+pub struct Storage(Arc<(Secret, Resolver, Box<dyn StorageBackend>, Mutex<HashMap<Id, u64>>, Arc<dyn Clock>)>);
 impl Storage {
-    pub fn start(secret: &Secret) -> Self {
-        let (tx, rx) = mpsc::build(mpsc::List::new());
-        let resolver = Resolver::start();
-        spawn(Self::run(resolver, secret.clone(), rx));
-        Storage(tx)
+    ///Missives older than retention are deleted even if their recipient never acknowledged them
+    pub fn start<B: StorageBackend>(secret: &Secret, resolver: Resolver, backend: B, retention: Option<u64>, clock: Arc<dyn Clock>) -> Self {
+        let storage = Storage(Arc::new((secret.clone(), resolver, Box::new(backend), Mutex::default(), clock)));
+        if let Some(retention) = retention {
+            spawn(storage.clone().expire(retention));
+        }
+        storage
     }
 
-    pub async fn request(&mut self, request: Request) -> AsyncRx<spsc::Array<Response>> {
+    ///Timestamps slots and missives, expires stamps and retention
+    pub fn now(&self) -> u64 {self.0.4.now()}
+
+    async fn expire(self, retention: u64) {
+        let mut interval = interval(Duration::from_nanos(retention.min(EXPIRE_INTERVAL)));
+        loop {
+            interval.tick().await;
+            let before = self.now().saturating_sub(retention);
+            self.blocking(move |backend| backend.expire(before)).await;
+        }
+    }
+
+    ///Session is the Name the connection authenticated as, if any
+    pub async fn request(&self, request: Request, session: Option<Name>) -> AsyncRx<spsc::Array<Response>> {
         let (stx, srx) = spsc::build(spsc::Array::new(request.max_responses()));
-        let _ = self.0.send((request, stx)).await;
+        spawn(self.clone().handle(request, session, stx));
         srx
     }
 
-    async fn run(resolver: Resolver, secret: Secret, rx: AsyncRx<mpsc::List<(Request, Responder)>>) {
-        let mut subscriptions = HashMap::<PublicKey, Vec<Responder>>::new();
-        let mut subscriptions_inbox = HashMap::<Name, Vec<Responder>>::new();
-        let connection = Connection::open("STORAGE.db").unwrap();
-        connection.execute("CREATE TABLE if not exists private(
-            key TEXT NOT NULL UNIQUE,
-            key_signature BLOB NOT NULL,
-            signature BLOB NOT NULL,
-            timestamp BLOB NOT NULL,
-            payload BLOB NOT NULL
-        );", []).unwrap();
-
-        connection.execute("CREATE TABLE if not exists inbox(
-            recipient TEXT NOT NULL,
-            timestamp INT NOT NULL,
-            signature BLOB NOT NULL,
-            payload BLOB NOT NULL
-        );", []).unwrap();
-
-        while let Ok((request, responder)) = rx.recv().await {
-            println!("request: {:?}", request);
-            match request {
-                Request::Create(signed) => {
-                    let hash = Id::hash(&signed.payload);
-                    let timestamp = now();
-                    let signature = secret.sign(Id::hash(&(signed.key, timestamp, hash)));
-                    match signed.verify() {
-                        Ok(()) => {
-                            let result = connection.query_row(
-                                "INSERT INTO private(key, signature, timestamp, key_signature, payload)
-                                 VALUES (?1, ?2, ?3, ?4, ?5) ON CONFLICT DO UPDATE SET key=?1
-                                 RETURNING signature, key_signature, timestamp, payload;",
-                                params![
-                                    postcard::to_allocvec(&signed.key).unwrap(),
-                                    postcard::to_allocvec(&signature).unwrap(),
-                                    postcard::to_allocvec(&timestamp).unwrap(),
-                                    postcard::to_allocvec(&signed.signature).unwrap(),
-                                    signed.payload
-                                ],
-                                |row| Ok((
-                                    postcard::from_bytes::<Signature>(&row.get::<_, Vec<u8>>("signature")?).unwrap(),
-                                    postcard::from_bytes::<u64>(&row.get::<_, Vec<u8>>("timestamp")?).unwrap(),
-                                    postcard::from_bytes::<KeySignature>(&row.get::<_, Vec<u8>>("key_signature")?).unwrap(),
-                                    row.get::<_, Vec<u8>>("payload")?
-                                ))
-                            ).unwrap();
-                            if signature == result.0 {
-                                if let Some(responders) = subscriptions.remove(&signed.key) {
-                                    let response = Response::Read(result.0.clone(), result.1, Some((result.2, result.3)));
-                                    for responder in responders {
-                                        let _ = responder.send(response.clone()).await;
-                                    }
-                                }
-                                let _ = responder.send(Response::Create(result.0, result.1)).await;
-                            } else {
-                                let _ = responder.send(Response::Read(result.0, result.1, Some((result.2, result.3)))).await;
+    ///A session only speaks for the mailboxes whose Policy names it as the owner
+    async fn authorize<T: Hash + Debug>(&self, authorized: Authorized<T>, session: Option<Name>) -> Result<(Mailbox, T), Response> {
+        match authorized {
+            Authorized::Signed(signed) => {
+                signed.verify().map_err(|e| Response::InvalidSignature(e.to_string()))?;
+                Ok((signed.key, signed.payload))
+            },
+            Authorized::Session(mailbox, payload) => {
+                let session = session.ok_or(Response::InvalidRequest("Not Authenticated".to_string()))?;
+                let policy = self.blocking(move |backend| backend.read(&mailbox)).await.and_then(|slot| postcard::from_bytes::<Policy>(&slot.3).ok());
+                match policy {
+                    Some(policy) if policy.owner == Some(session) => Ok((mailbox, payload)),
+                    _ => Err(Response::InvalidRequest("Not The Mailbox Owner".to_string()))
+                }
+            }
+        }
+    }
+
+    async fn blocking<R: Send + 'static>(&self, f: impl FnOnce(&dyn StorageBackend) -> R + Send + 'static) -> R {
+        let storage = self.0.clone();
+        spawn_blocking(move || f(&*storage.2)).await.unwrap()
+    }
+
+    ///Records a stamp as used, stamps are only remembered for as long as they would be accepted
+    fn spend(&self, id: Id, timestamp: u64) -> Result<(), String> {
+        let mut spent = self.0.3.lock().unwrap();
+        let oldest = self.now().saturating_sub(WINDOW);
+        spent.retain(|_, t| *t >= oldest);
+        if spent.insert(id, timestamp).is_some() {Err("Stamp Already Used".to_string())?}
+        Ok(())
+    }
+
+    async fn handle(self, request: Request, session: Option<Name>, responder: Responder) {
+        println!("request: {:?}", request);
+        let secret = &self.0.0;
+        match request {
+            Request::Create(signed) => {
+                let hash = Id::hash(&signed.payload);
+                let timestamp = self.now();
+                let signature = secret.sign(Id::hash(&(signed.key, timestamp, hash)));
+                match signed.verify() {
+                    Ok(()) => {
+                        let key = signed.key;
+                        let slot = (signature.clone(), timestamp, signed.signature, signed.payload);
+                        let (result, created, subscribers) = self.blocking(move |backend| {
+                            let result = backend.create(key, slot);
+                            let created = signature == result.0;
+                            let subscribers = if created {
+                                backend.subscriptions().slot(&key).remove(&key).unwrap_or_default()
+                            } else {vec![]};
+                            (result, created, subscribers)
+                        }).await;
+                        if created {
+                            let response = Response::Read(result.0.clone(), result.1, Some((result.2, result.3)));
+                            for subscriber in subscribers {
+                                let _ = subscriber.send(response.clone()).await;
                             }
-                        },
-                        Err(e) => {let _ = responder.send(Response::InvalidSignature(e.to_string())).await;},
-                    }
-                },
-                Request::Read(key, subscribe) => {
-                    if let Some(read) = connection.query_row(
-                        "SELECT signature, timestamp, key_signature, payload FROM private WHERE key=?1",
-                        [postcard::to_allocvec(&key).unwrap()], |row| Ok(Response::Read(
-                            postcard::from_bytes::<Signature>(&row.get::<_, Vec<u8>>("signature")?).unwrap(),
-                            postcard::from_bytes::<u64>(&row.get::<_, Vec<u8>>("timestamp")?).unwrap(),
-                            Some((
-                                postcard::from_bytes::<KeySignature>(&row.get::<_, Vec<u8>>("key_signature")?).unwrap(),
-                                row.get::<_, Vec<u8>>("payload")?
-                            ))
-                        ))
-                    ).optional().unwrap() {
-                        let _ = responder.send(read).await;
-                    } else {
-                        let timestamp = now();
-                        let id = Id::hash(&(key, timestamp, Id::MIN));
-                        let _ = responder.send(Response::Read(secret.sign(id), timestamp, None)).await;
-                        if subscribe {
-                            subscriptions.entry(key).or_default().push(responder);
+                            let _ = responder.send(Response::Create(result.0, result.1)).await;
+                        } else {
+                            let _ = responder.send(Response::Read(result.0, result.1, Some((result.2, result.3)))).await;
                         }
-                    }
-                },
-                Request::Send(recipient, payload) => {
-                    let timestamp = now();
-                    let signature = secret.sign(Id::hash(&(recipient, timestamp, &payload)));
-                    connection.execute(
-                        "INSERT INTO inbox(recipient, timestamp, signature, payload) VALUES (?1, ?2, ?3, ?4)",
-                        params![
-                            recipient.to_string(),
-                            timestamp as isize,
-                            serde_json::to_vec(&signature).unwrap(),
-                            payload,
-                        ],
-                    ).unwrap();
-                    let _ = responder.send(Response::Create(signature.clone(), timestamp)).await;
-                    if let Some(responders) = subscriptions_inbox.remove(&recipient) {
-                        let response = Response::Inbox(vec![(signature, timestamp, payload)]);
-                        for responder in responders {
-                            let _ = responder.send(response.clone()).await;
+                    },
+                    Err(e) => {let _ = responder.send(Response::InvalidSignature(e.to_string())).await;},
+                }
+            },
+            Request::Read(key, subscribe) => {
+                let (secret, clock) = (secret.clone(), self.0.4.clone());
+                let read = self.blocking(move |backend| {
+                    let mut subscribers = backend.subscriptions().slot(&key);
+                    match backend.read(&key) {
+                        Some((signature, timestamp, key_signature, payload)) => Some((Response::Read(signature, timestamp, Some((key_signature, payload))), responder)),
+                        None => {
+                            let timestamp = clock.now();
+                            let id = Id::hash(&(key, timestamp, Id::MIN));
+                            let response = Response::Read(secret.sign(id), timestamp, None);
+                            if subscribe {
+                                let _ = responder.try_send(response);
+                                subscribers.entry(key).or_default().push(responder);
+                                None
+                            } else {Some((response, responder))}
                         }
                     }
-                },
-                Request::Receive(signed) => {
-                    let identity = resolver.resolve(signed.signer, None).await;
-                    match signed.verify(&identity, &[]) {
-                        Ok(()) => {
-                            let recipient = signed.signer;
-                            let (ordering, timestamp) = signed.payload;
-                            let query = format!("SELECT signature, timestamp, payload FROM inbox WHERE recipient='{recipient}' AND timestamp{ordering}'{timestamp}'");
-                            let results = connection.prepare(&query).unwrap().query_map(
-                                [], |r| Ok((
-                                    serde_json::from_slice::<Signature>(&r.get::<_, Vec<u8>>(0)?).unwrap(),
-                                    r.get::<_, isize>(1)? as u64,
-                                    r.get::<_, Vec<u8>>(2)?,
-                                ))
-                            ).unwrap().collect::<Result<Vec<_>, rusqlite::Error>>().unwrap();
-                            if results.is_empty() {
-                                subscriptions_inbox.entry(signed.signer).or_default().push(responder);
-                            } else {
-                                let _ = responder.send(Response::Inbox(results)).await;
-                            }
+                }).await;
+                if let Some((response, responder)) = read {
+                    let _ = responder.send(response).await;
+                }
+            },
+            Request::ReadMany(keys) => {
+                if keys.len() > MAX_PAGE as usize {
+                    let _ = responder.send(Response::InvalidRequest("Too Many Keys".to_string())).await;
+                    return;
+                }
+                let (secret, clock) = (secret.clone(), self.0.4.clone());
+                let reads = self.blocking(move |backend| {
+                    let timestamp = clock.now();
+                    keys.iter().zip(backend.read_many(&keys)).map(|(key, slot)| match slot {
+                        Some((signature, timestamp, key_signature, payload)) => (signature, timestamp, Some((key_signature, payload))),
+                        None => (secret.sign(Id::hash(&(key, timestamp, Id::MIN))), timestamp, None)
+                    }).collect()
+                }).await;
+                let _ = responder.send(Response::ReadMany(reads)).await;
+            },
+            Request::Log(signed) => {
+                match signed.verify() {
+                    Ok(()) => {
+                        let (log, hash, secret, clock) = (signed.key, Id::hash(&signed.payload), secret.clone(), self.0.4.clone());
+                        let sign: Sign = Box::new(move |index| {
+                            let timestamp = clock.now();
+                            (secret.sign(Id::hash(&(log, index, timestamp, hash))), timestamp, signed.signature, signed.payload)
+                        });
+                        let (index, entry, subscribers) = self.blocking(move |backend| {
+                            let (index, entry) = backend.log(log, sign);
+                            (index, entry, backend.subscriptions().log(&log, index).remove(&(log, index)).unwrap_or_default())
+                        }).await;
+                        let _ = responder.send(Response::Logged(index, entry.0.clone(), entry.1)).await;
+                        let response = Response::Entry(index, entry.0, entry.1, Some((entry.2, entry.3)));
+                        for subscriber in subscribers {
+                            let _ = subscriber.send(response.clone()).await;
+                        }
+                    },
+                    Err(e) => {let _ = responder.send(Response::InvalidSignature(e.to_string())).await;},
+                }
+            },
+            Request::Entry(log, index, subscribe) => {
+                let (secret, clock) = (secret.clone(), self.0.4.clone());
+                let read = self.blocking(move |backend| {
+                    let mut subscribers = backend.subscriptions().log(&log, index);
+                    match backend.entry(&log, index) {
+                        Err(pruned) => {
+                            let timestamp = clock.now();
+                            let signature = secret.sign(Id::hash(&(log, pruned, timestamp, Id::hash(PRUNED))));
+                            Some((Response::Pruned(pruned, signature, timestamp), responder))
                         },
-                        Err(e) => {let _ = responder.send(Response::InvalidSignature(e.to_string())).await;}
+                        Ok(Some((signature, timestamp, key_signature, payload))) => Some((Response::Entry(index, signature, timestamp, Some((key_signature, payload))), responder)),
+                        Ok(None) => {
+                            let timestamp = clock.now();
+                            let response = Response::Entry(index, secret.sign(Id::hash(&(log, index, timestamp, Id::MIN))), timestamp, None);
+                            if subscribe {
+                                let _ = responder.try_send(response);
+                                subscribers.entry((log, index)).or_default().push(responder);
+                                None
+                            } else {Some((response, responder))}
+                        }
                     }
+                }).await;
+                if let Some((response, responder)) = read {
+                    let _ = responder.send(response).await;
+                }
+            },
+            Request::Prune(signed) => {
+                match signed.verify() {
+                    Ok(()) => {
+                        let (log, (below, checkpoint)) = (signed.key, signed.payload);
+                        let covered = self.blocking(move |backend| backend.read(&checkpoint)).await
+                            .and_then(|slot| postcard::take_from_bytes::<(PublicKey, u64)>(&slot.3).ok())
+                            .is_some_and(|((covers, position), _)| covers == log && position >= below);
+                        if !covered {
+                            let _ = responder.send(Response::InvalidRequest("No Checkpoint Covers The Prune".to_string())).await;
+                            return;
+                        }
+                        let pruned = self.blocking(move |backend| backend.prune(log, below)).await;
+                        let timestamp = self.now();
+                        let signature = secret.sign(Id::hash(&(log, pruned, timestamp, Id::hash(PRUNED))));
+                        let _ = responder.send(Response::Pruned(pruned, signature, timestamp)).await;
+                    },
+                    Err(e) => {let _ = responder.send(Response::InvalidSignature(e.to_string())).await;}
+                }
+            },
+            Request::Send(recipient, payload, stamp) => {
+                let policy = self.blocking(move |backend| backend.read(&recipient)).await.and_then(|slot| postcard::from_bytes::<Policy>(&slot.3).ok());
+                let Some(policy) = policy else {
+                    let _ = responder.send(Response::InvalidRequest("Unknown Mailbox".to_string())).await;
+                    return;
+                };
+                if let Err(e) = stamp.verify(&self.0.1, recipient, &policy, &payload, self.now()).await.and_then(|id| self.spend(id, stamp.timestamp())) {
+                    let _ = responder.send(Response::InvalidRequest(e)).await;
+                    return;
+                }
+                let timestamp = self.now();
+                let signature = secret.sign(Id::hash(&(recipient, timestamp, &payload)));
+                let missive = (signature.clone(), timestamp, payload);
+                let (cursor, subscribers) = self.blocking({let missive = missive.clone(); move |backend| {
+                    let cursor = backend.append(recipient, missive);
+                    (cursor, backend.subscriptions().inbox(&recipient).remove(&recipient).unwrap_or_default())
+                }}).await;
+                let _ = responder.send(Response::Create(signature, timestamp)).await;
+                let response = Response::Inbox(vec![(cursor, missive)], false);
+                for subscriber in subscribers {
+                    let _ = subscriber.send(response.clone()).await;
+                }
+            },
+            Request::Receive(authorized) => {
+                match self.authorize(authorized, session).await {
+                    Ok((recipient, page)) => {
+                        let limit = page.limit.clamp(1, MAX_PAGE) as usize;
+                        let mut after = page.after;
+                        let mut responder = Some(responder);
+                        while let Some(r) = responder.take() {
+                            let first = after == page.after;
+                            let missives = self.blocking(move |backend| {
+                                let mut subscribers = backend.subscriptions().inbox(&recipient);
+                                let mut missives = backend.query(&recipient, after, limit+1);
+                                if missives.is_empty() && first {
+                                    subscribers.entry(recipient).or_default().push(r);
+                                    None
+                                } else {
+                                    let more = missives.len() > limit;
+                                    missives.truncate(limit);
+                                    Some((missives, more, r))
+                                }
+                            }).await;
+                            if let Some((missives, more, r)) = missives {
+                                after = missives.last().map(|(cursor, _)| *cursor).unwrap_or(after);
+                                if r.send(Response::Inbox(missives, more)).await.is_ok() && more {
+                                    responder = Some(r);
+                                }
+                            }
+                        }
+                    },
+                    Err(response) => {let _ = responder.send(response).await;}
                 }
+            },
+            Request::Ack(authorized) => {
+                match self.authorize(authorized, session).await {
+                    Ok((recipient, cursor)) => {
+                        self.blocking(move |backend| backend.ack(&recipient, cursor)).await;
+                        let _ = responder.send(Response::Ack(cursor)).await;
+                    },
+                    Err(response) => {let _ = responder.send(response).await;}
+                }
+            },
+            Request::Authenticate(..) | Request::Ticket => {
+                let _ = responder.send(Response::InvalidRequest("Handled By The Connection".to_string())).await;
             }
         }
     }
@@ -202,7 +477,21 @@ impl Storage {
 #[cfg(test)]
 mod test {
     use super::*;
-    use crate::names::{Resolver, secp256k1::SecretKey};
+    use crate::names::{Resolver, SystemClock, ManualClock, now, secp256k1::SecretKey};
+    use crate::stamp::DEFAULT_DIFFICULTY;
+
+    fn send(recipient: Mailbox, payload: Vec<u8>) -> Request {
+        let stamp = Stamp::work(recipient, Id::hash(&payload), DEFAULT_DIFFICULTY, now()).unwrap();
+        Request::Send(recipient, payload, stamp)
+    }
+
+    ///Opens a fresh mailbox with the default policy, returning its key
+    async fn open(storage: &Storage) -> SecretKey {
+        let key = SecretKey::new();
+        let policy = postcard::to_allocvec(&Policy::default()).unwrap();
+        assert!(matches!(storage.request(Request::Create(KeySigned::new(&key, policy)), None).await.recv().await.unwrap(), Response::Create(..)));
+        key
+    }
 
     #[tokio::test]
     async fn create() {
@@ -210,12 +499,12 @@ mod test {
         let server_name = server.name();
         let resolver = Resolver::start();
         let identity = resolver.resolve(server_name, None).await;
-        let mut storage = Storage::start(&server);
+        let storage = Storage::start(&server, Resolver::start(), Memory::default(), None, Arc::new(SystemClock));
 
         let file_key = SecretKey::new();
         let content = b"my file contents".to_vec();
 
-        if let Response::Create(signature, timestamp) = storage.request(Request::Create(KeySigned::new(&file_key, content.clone()))).await.recv().await.unwrap() {
+        if let Response::Create(signature, timestamp) = storage.request(Request::Create(KeySigned::new(&file_key, content.clone())), None).await.recv().await.unwrap() {
             signature.verify(&identity, &[], Id::hash(&(file_key.public_key(), timestamp, Id::hash(&content)))).unwrap();
         } else {panic!("Unexpected Response");}
     }
@@ -226,23 +515,270 @@ mod test {
         let server_name = server.name();
         let resolver = Resolver::start();
         let identity = resolver.resolve(server_name, None).await;
-        let mut storage = Storage::start(&server);
+        let storage = Storage::start(&server, Resolver::start(), Memory::default(), None, Arc::new(SystemClock));
 
-        let bob = Secret::new();
-        let bob_name = bob.name();
+        let bob = open(&storage).await;
+        let mailbox = bob.public_key();
 
         let content = b"my file contents".to_vec();
 
-        let timestamp = if let Response::Create(signature, timestamp) = storage.request(Request::Send(bob_name, content.clone())).await.recv().await.unwrap() {
-            signature.verify(&identity, &[], Id::hash(&(bob_name, timestamp, &content))).unwrap();
+        let timestamp = if let Response::Create(signature, timestamp) = storage.request(send(mailbox, content.clone()), None).await.recv().await.unwrap() {
+            signature.verify(&identity, &[], Id::hash(&(mailbox, timestamp, &content))).unwrap();
             timestamp
         } else {panic!("Unexpected Response");};
 
-        let request = storage.request(Request::Receive(Signed::new(&bob, (Compare::Greater, 0)))).await;
-        if let Response::Inbox(received) = request.recv().await.unwrap() {
-            for (signature, _, content) in received {
-                signature.verify(&identity, &[], Id::hash(&(bob_name, timestamp, &content))).unwrap();
+        let request = storage.request(Request::Receive(Authorized::Signed(KeySigned::new(&bob, Page{after: 0, limit: MAX_PAGE}))), None).await;
+        if let Response::Inbox(received, false) = request.recv().await.unwrap() {
+            for (_, (signature, _, content)) in received {
+                signature.verify(&identity, &[], Id::hash(&(mailbox, timestamp, &content))).unwrap();
             }
         } else {panic!("Unexpected Response");}
     }
+
+    #[tokio::test]
+    async fn sharded() {
+        let server = Secret::new();
+        let directory = std::env::temp_dir().join(format!("air_sharded_{}", Id::random()));
+        let storage = Storage::start(&server, Resolver::start(), Sharded::open(&directory, 4), None, Arc::new(SystemClock));
+
+        let file_key = SecretKey::new();
+        let content = b"first".to_vec();
+        let created = storage.request(Request::Create(KeySigned::new(&file_key, content.clone())), None).await.recv().await.unwrap();
+        assert!(matches!(created, Response::Create(..)));
+
+        match storage.request(Request::Create(KeySigned::new(&file_key, b"second".to_vec())), None).await.recv().await.unwrap() {
+            Response::Read(_, _, Some((_, payload))) => assert_eq!(payload, content),
+            response => panic!("Unexpected Response: {response:?}")
+        }
+        let _ = std::fs::remove_dir_all(directory);
+    }
+
+    #[tokio::test]
+    async fn migrate() {
+        let path = std::env::temp_dir().join(format!("air_migrate_{}.db", Id::random()));
+        let connection = rusqlite::Connection::open(&path).unwrap();
+        connection.execute("CREATE TABLE inbox(recipient TEXT NOT NULL, timestamp INT NOT NULL, signature BLOB NOT NULL, payload BLOB NOT NULL);", []).unwrap();
+        connection.execute("INSERT INTO inbox VALUES ('bob', 1, x'00', x'00')", []).unwrap();
+        drop(connection);
+
+        let storage = Storage::start(&Secret::new(), Resolver::start(), Sqlite::open(&path), None, Arc::new(SystemClock));
+        let bob = open(&storage).await;
+        storage.request(send(bob.public_key(), b"hello".to_vec()), None).await.recv().await.unwrap();
+        assert!(matches!(storage.request(Request::Receive(Authorized::Signed(KeySigned::new(&bob, Page{after: 0, limit: MAX_PAGE}))), None).await.recv().await.unwrap(), Response::Inbox(page, false) if page.len() == 1));
+
+        let connection = rusqlite::Connection::open(&path).unwrap();
+        assert_eq!(connection.pragma_query_value(None, "user_version", |r| r.get::<_, u32>(0)).unwrap(), 1);
+        assert_eq!(connection.query_row("SELECT COUNT(*) FROM inbox_legacy", [], |r| r.get::<_, i64>(0)).unwrap(), 1);
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[tokio::test]
+    async fn subscribe() {
+        let server = Secret::new();
+        let storage = Storage::start(&server, Resolver::start(), Memory::default(), None, Arc::new(SystemClock));
+
+        let file_key = SecretKey::new();
+        let content = b"late".to_vec();
+        let subscription = storage.request(Request::Read(file_key.public_key(), true), None).await;
+        assert!(matches!(subscription.recv().await.unwrap(), Response::Read(_, _, None)));
+
+        let created = storage.request(Request::Create(KeySigned::new(&file_key, content.clone())), None).await;
+        assert!(matches!(created.recv().await.unwrap(), Response::Create(..)));
+        match subscription.recv().await.unwrap() {
+            Response::Read(_, _, Some((_, payload))) => assert_eq!(payload, content),
+            response => panic!("Unexpected Response: {response:?}")
+        }
+    }
+
+    #[tokio::test]
+    async fn pages() {
+        let server = Secret::new();
+        let storage = Storage::start(&server, Resolver::start(), Memory::default(), None, Arc::new(SystemClock));
+        let bob = open(&storage).await;
+
+        for i in 0..5u8 {
+            storage.request(send(bob.public_key(), vec![i]), None).await.recv().await.unwrap();
+        }
+
+        let pages = storage.request(Request::Receive(Authorized::Signed(KeySigned::new(&bob, Page{after: 0, limit: 2}))), None).await;
+        let mut received = vec![];
+        loop {match pages.recv().await.unwrap() {
+            Response::Inbox(page, more) => {
+                assert!(page.len() <= 2);
+                received.extend(page.into_iter().map(|(_, (_, _, payload))| payload));
+                if !more {break}
+            },
+            response => panic!("Unexpected Response: {response:?}")
+        }}
+        assert_eq!(received, (0..5u8).map(|i| vec![i]).collect::<Vec<_>>());
+    }
+
+    #[tokio::test]
+    async fn ack() {
+        let server = Secret::new();
+        let storage = Storage::start(&server, Resolver::start(), Memory::default(), None, Arc::new(SystemClock));
+        let bob = open(&storage).await;
+
+        for i in 0..3u8 {
+            storage.request(send(bob.public_key(), vec![i]), None).await.recv().await.unwrap();
+        }
+        let cursors = match storage.request(Request::Receive(Authorized::Signed(KeySigned::new(&bob, Page{after: 0, limit: MAX_PAGE}))), None).await.recv().await.unwrap() {
+            Response::Inbox(page, false) => page.into_iter().map(|(cursor, _)| cursor).collect::<Vec<_>>(),
+            response => panic!("Unexpected Response: {response:?}")
+        };
+
+        let acked = storage.request(Request::Ack(Authorized::Signed(KeySigned::new(&bob, cursors[1]))), None).await.recv().await.unwrap();
+        assert_eq!(acked, Response::Ack(cursors[1]));
+        match storage.request(Request::Receive(Authorized::Signed(KeySigned::new(&bob, Page{after: 0, limit: MAX_PAGE}))), None).await.recv().await.unwrap() {
+            Response::Inbox(page, false) => assert_eq!(page.into_iter().map(|(_, (_, _, p))| p).collect::<Vec<_>>(), vec![vec![2]]),
+            response => panic!("Unexpected Response: {response:?}")
+        }
+    }
+
+    #[tokio::test]
+    async fn mailbox() {
+        let storage = Storage::start(&Secret::new(), Resolver::start(), Memory::default(), None, Arc::new(SystemClock));
+        let closed = SecretKey::new().public_key();
+        assert!(matches!(storage.request(send(closed, b"hello".to_vec()), None).await.recv().await.unwrap(), Response::InvalidRequest(_)));
+
+        let bob = open(&storage).await;
+        assert!(matches!(storage.request(send(bob.public_key(), b"hello".to_vec()), None).await.recv().await.unwrap(), Response::Create(..)));
+    }
+
+    #[tokio::test]
+    async fn session() {
+        let storage = Storage::start(&Secret::new(), Resolver::start(), Memory::default(), None, Arc::new(SystemClock));
+        let (bob, eve) = (Secret::new(), Secret::new());
+        let key = SecretKey::new();
+        let policy = postcard::to_allocvec(&Policy{owner: Some(bob.name()), ..Policy::default()}).unwrap();
+        storage.request(Request::Create(KeySigned::new(&key, policy)), None).await.recv().await.unwrap();
+        let unowned = open(&storage).await.public_key();
+        storage.request(send(key.public_key(), b"hello".to_vec()), None).await.recv().await.unwrap();
+
+        let page = Page{after: 0, limit: MAX_PAGE};
+        for (mailbox, session) in [(key.public_key(), None), (key.public_key(), Some(eve.name())), (unowned, Some(bob.name()))] {
+            let rejected = storage.request(Request::Receive(Authorized::Session(mailbox, page)), session).await.recv().await.unwrap();
+            assert!(matches!(rejected, Response::InvalidRequest(_)));
+        }
+        match storage.request(Request::Receive(Authorized::Session(key.public_key(), page)), Some(bob.name())).await.recv().await.unwrap() {
+            Response::Inbox(received, false) => assert_eq!(received.len(), 1),
+            response => panic!("Unexpected Response: {response:?}")
+        }
+    }
+
+    #[tokio::test]
+    async fn stamps() {
+        let server = Secret::new();
+        let storage = Storage::start(&server, Resolver::start(), Memory::default(), None, Arc::new(SystemClock));
+        let bob = open(&storage).await;
+
+        let content = b"spam".to_vec();
+        let request = send(bob.public_key(), content.clone());
+        assert!(matches!(storage.request(request.clone(), None).await.recv().await.unwrap(), Response::Create(..)));
+        assert!(matches!(storage.request(request, None).await.recv().await.unwrap(), Response::InvalidRequest(_)));
+
+        let expired = Request::Send(bob.public_key(), content, Stamp::Work(0, 0));
+        assert!(matches!(storage.request(expired, None).await.recv().await.unwrap(), Response::InvalidRequest(_)));
+    }
+
+    #[tokio::test]
+    async fn read_many() {
+        let server = Secret::new();
+        let identity = Resolver::start().resolve(server.name(), None).await;
+        let storage = Storage::start(&server, Resolver::start(), Memory::default(), None, Arc::new(SystemClock));
+
+        let keys = (0..3).map(|_| SecretKey::new()).collect::<Vec<_>>();
+        for key in &keys[..2] {
+            storage.request(Request::Create(KeySigned::new(key, key.public_key().to_string().into_bytes())), None).await.recv().await.unwrap();
+        }
+        let publics = keys.iter().map(|key| key.public_key()).collect::<Vec<_>>();
+        match storage.request(Request::ReadMany(publics.clone()), None).await.recv().await.unwrap() {
+            Response::ReadMany(reads) => {
+                assert_eq!(reads.len(), 3);
+                for (public, read) in publics.iter().zip(&reads[..2]) {
+                    assert_eq!(read.2.as_ref().map(|(_, payload)| payload.clone()), Some(public.to_string().into_bytes()));
+                }
+                let (signature, timestamp, data) = &reads[2];
+                assert!(data.is_none());
+                signature.verify(&identity, &[], Id::hash(&(publics[2], *timestamp, Id::MIN))).unwrap();
+            },
+            response => panic!("Unexpected Response: {response:?}")
+        }
+        let too_many = vec![publics[0]; MAX_PAGE as usize + 1];
+        assert!(matches!(storage.request(Request::ReadMany(too_many), None).await.recv().await.unwrap(), Response::InvalidRequest(_)));
+    }
+
+    ///A slot saying it checkpoints the log up to the position, which is all the server can read of one
+    async fn checkpoint(storage: &Storage, log: PublicKey, position: u64) -> PublicKey {
+        let slot = SecretKey::new();
+        storage.request(Request::Create(KeySigned::new(&slot, postcard::to_allocvec(&(log, position)).unwrap())), None).await.recv().await.unwrap();
+        slot.public_key()
+    }
+
+    async fn log(storage: Storage, identity: crate::names::Identity) {
+        let key = SecretKey::new();
+        let log = key.public_key();
+        for (i, payload) in [b"first".to_vec(), b"second".to_vec()].into_iter().enumerate() {
+            match storage.request(Request::Log(KeySigned::new(&key, payload.clone())), None).await.recv().await.unwrap() {
+                Response::Logged(index, signature, timestamp) => {
+                    assert_eq!(index, i as u64);
+                    signature.verify(&identity, &[], Id::hash(&(log, index, timestamp, Id::hash(&payload)))).unwrap();
+                },
+                response => panic!("Unexpected Response: {response:?}")
+            }
+        }
+
+        let subscription = storage.request(Request::Entry(log, 2, true), None).await;
+        match subscription.recv().await.unwrap() {
+            Response::Entry(2, signature, timestamp, None) => signature.verify(&identity, &[], Id::hash(&(log, 2u64, timestamp, Id::MIN))).unwrap(),
+            response => panic!("Unexpected Response: {response:?}")
+        }
+        storage.request(Request::Log(KeySigned::new(&key, b"third".to_vec())), None).await.recv().await.unwrap();
+        assert!(matches!(subscription.recv().await.unwrap(), Response::Entry(2, _, _, Some((_, payload))) if payload == b"third"));
+        assert!(matches!(storage.request(Request::Entry(log, 0, false), None).await.recv().await.unwrap(), Response::Entry(0, _, _, Some((_, payload))) if payload == b"first"));
+
+        //Only as far as a checkpoint of this log covers, never past the end of the log, and never back down
+        let (two, ten) = (checkpoint(&storage, log, 2).await, checkpoint(&storage, log, 10).await);
+        let other = checkpoint(&storage, SecretKey::new().public_key(), 10).await;
+        for (below, slot) in [(3, two), (2, other), (2, SecretKey::new().public_key())] {
+            let prune = Request::Prune(KeySigned::new(&key, (below, slot)));
+            assert!(matches!(storage.request(prune, None).await.recv().await.unwrap(), Response::InvalidRequest(_)));
+        }
+        assert!(matches!(storage.request(Request::Prune(KeySigned::new(&key, (2, two))), None).await.recv().await.unwrap(), Response::Pruned(2, _, _)));
+        assert!(matches!(storage.request(Request::Prune(KeySigned::new(&key, (10, ten))), None).await.recv().await.unwrap(), Response::Pruned(3, _, _)));
+        match storage.request(Request::Entry(log, 1, false), None).await.recv().await.unwrap() {
+            Response::Pruned(3, signature, timestamp) => signature.verify(&identity, &[], Id::hash(&(log, 3u64, timestamp, Id::hash(PRUNED)))).unwrap(),
+            response => panic!("Unexpected Response: {response:?}")
+        }
+        assert!(matches!(storage.request(Request::Prune(KeySigned::new(&key, (1, two))), None).await.recv().await.unwrap(), Response::Pruned(3, _, _)));
+        assert!(matches!(storage.request(Request::Log(KeySigned::new(&key, b"fourth".to_vec())), None).await.recv().await.unwrap(), Response::Logged(3, _, _)));
+    }
+
+    #[tokio::test]
+    async fn logs() {
+        let server = Secret::new();
+        let identity = Resolver::start().resolve(server.name(), None).await;
+        log(Storage::start(&server, Resolver::start(), Memory::default(), None, Arc::new(SystemClock)), identity.clone()).await;
+
+        let directory = std::env::temp_dir().join(format!("air_log_{}", Id::random()));
+        log(Storage::start(&server, Resolver::start(), Sharded::open(&directory, 2), None, Arc::new(SystemClock)), identity).await;
+        let _ = std::fs::remove_dir_all(directory);
+    }
+
+    #[tokio::test]
+    async fn clock() {
+        let clock = Arc::new(ManualClock::new(1_000));
+        let storage = Storage::start(&Secret::new(), Resolver::start(), Memory::default(), None, clock.clone());
+        let bob = open(&storage).await;
+
+        match storage.request(Request::Create(KeySigned::new(&SecretKey::new(), vec![])), None).await.recv().await.unwrap() {
+            Response::Create(_, timestamp) => assert_eq!(timestamp, 1_000),
+            response => panic!("Unexpected Response: {response:?}")
+        }
+
+        let payload = b"late".to_vec();
+        let stamp = Stamp::work(bob.public_key(), Id::hash(&payload), DEFAULT_DIFFICULTY, clock.now()).unwrap();
+        clock.advance(WINDOW + 1);
+        assert_eq!(storage.request(Request::Send(bob.public_key(), payload, stamp), None).await.recv().await.unwrap(), Response::InvalidRequest("Stamp Expired".to_string()));
+    }
 }
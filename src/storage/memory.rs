@@ -0,0 +1,81 @@
+use std::collections::{HashMap, BTreeMap};
+use std::sync::RwLock;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::ops::Bound;
+
+use crate::names::secp256k1::PublicKey;
+
+use super::{StorageBackend, Subscriptions, Slot, Entry, Sign, Missive, Cursor, Mailbox};
+
+///Keeps everything in process, for tests and embedded servers that do not need to outlive the process
+#[derive(Default)]
+pub struct Memory {
+    slots: RwLock<HashMap<PublicKey, Slot>>,
+    logs: RwLock<HashMap<PublicKey, (u64, BTreeMap<u64, Entry>)>>,//Pruned, Entries
+    inbox: RwLock<HashMap<Mailbox, BTreeMap<Cursor, Missive>>>,
+    cursor: AtomicU64,
+    subscriptions: Subscriptions,
+}
+
+impl StorageBackend for Memory {
+    fn create(&self, key: PublicKey, slot: Slot) -> Slot {
+        self.slots.write().unwrap().entry(key).or_insert(slot).clone()
+    }
+
+    fn read(&self, key: &PublicKey) -> Option<Slot> {self.slots.read().unwrap().get(key).cloned()}
+
+    fn log(&self, log: PublicKey, sign: Sign) -> (u64, Entry) {
+        let mut logs = self.logs.write().unwrap();
+        let (pruned, entries) = logs.entry(log).or_default();
+        let index = entries.last_key_value().map(|(index, _)| index + 1).unwrap_or(*pruned);
+        let entry = sign(index);
+        entries.insert(index, entry.clone());
+        (index, entry)
+    }
+
+    fn entry(&self, log: &PublicKey, index: u64) -> Result<Option<Entry>, u64> {
+        match self.logs.read().unwrap().get(log) {
+            Some((pruned, _)) if index < *pruned => Err(*pruned),
+            Some((_, entries)) => Ok(entries.get(&index).cloned()),
+            None => Ok(None)
+        }
+    }
+
+    fn prune(&self, log: PublicKey, below: u64) -> u64 {
+        let mut logs = self.logs.write().unwrap();
+        let (pruned, entries) = logs.entry(log).or_default();
+        let end = entries.last_key_value().map(|(index, _)| index + 1).unwrap_or(*pruned);
+        *pruned = below.min(end).max(*pruned);
+        *entries = entries.split_off(&*pruned);
+        *pruned
+    }
+
+    fn append(&self, recipient: Mailbox, missive: Missive) -> Cursor {
+        let mut inbox = self.inbox.write().unwrap();
+        let cursor = self.cursor.fetch_add(1, Ordering::Relaxed) + 1;
+        inbox.entry(recipient).or_default().insert(cursor, missive);
+        cursor
+    }
+
+    fn query(&self, recipient: &Mailbox, after: Cursor, limit: usize) -> Vec<(Cursor, Missive)> {
+        self.inbox.read().unwrap().get(recipient).map(|missives|
+            missives.range((Bound::Excluded(after), Bound::Unbounded)).take(limit).map(|(c, m)| (*c, m.clone())).collect()
+        ).unwrap_or_default()
+    }
+
+    fn ack(&self, recipient: &Mailbox, cursor: Cursor) {
+        let mut inbox = self.inbox.write().unwrap();
+        if let Some(missives) = inbox.get_mut(recipient) {
+            *missives = missives.split_off(&cursor.saturating_add(1));
+            if missives.is_empty() {inbox.remove(recipient);}
+        }
+    }
+
+    fn expire(&self, before: u64) {
+        let mut inbox = self.inbox.write().unwrap();
+        inbox.values_mut().for_each(|missives| missives.retain(|_, m| m.1 >= before));
+        inbox.retain(|_, missives| !missives.is_empty());
+    }
+
+    fn subscriptions(&self) -> &Subscriptions {&self.subscriptions}
+}
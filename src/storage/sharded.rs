@@ -0,0 +1,33 @@
+use std::path::Path;
+
+use crate::names::secp256k1::PublicKey;
+
+use super::{StorageBackend, Subscriptions, Sqlite, Slot, Entry, Sign, Missive, Cursor, Mailbox, shard};
+
+///Spreads slots and inboxes over several SQLite files by the hash of their key, each with its own writer
+pub struct Sharded(Vec<Sqlite>, Subscriptions);
+impl Sharded {
+    pub fn open<P: AsRef<Path>>(directory: P, shards: usize) -> Self {
+        assert!(shards > 0, "Sharded storage requires at least one shard");
+        Sharded((0..shards).map(|i| Sqlite::open(directory.as_ref().join(format!("STORAGE_{i}.db")))).collect(), Subscriptions::default())
+    }
+}
+
+impl StorageBackend for Sharded {
+    fn create(&self, key: PublicKey, slot: Slot) -> Slot {self.0[shard(&key, self.0.len())].create(key, slot)}
+    fn read(&self, key: &PublicKey) -> Option<Slot> {self.0[shard(key, self.0.len())].read(key)}
+
+    fn log(&self, log: PublicKey, sign: Sign) -> (u64, Entry) {self.0[shard(&log, self.0.len())].log(log, sign)}
+    fn entry(&self, log: &PublicKey, index: u64) -> Result<Option<Entry>, u64> {self.0[shard(log, self.0.len())].entry(log, index)}
+    fn prune(&self, log: PublicKey, below: u64) -> u64 {self.0[shard(&log, self.0.len())].prune(log, below)}
+
+    fn append(&self, recipient: Mailbox, missive: Missive) -> Cursor {self.0[shard(&recipient, self.0.len())].append(recipient, missive)}
+    fn query(&self, recipient: &Mailbox, after: Cursor, limit: usize) -> Vec<(Cursor, Missive)> {
+        self.0[shard(recipient, self.0.len())].query(recipient, after, limit)
+    }
+
+    fn ack(&self, recipient: &Mailbox, cursor: Cursor) {self.0[shard(recipient, self.0.len())].ack(recipient, cursor)}
+    fn expire(&self, before: u64) {self.0.iter().for_each(|shard| shard.expire(before))}
+
+    fn subscriptions(&self) -> &Subscriptions {&self.1}
+}
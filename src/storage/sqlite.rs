@@ -0,0 +1,289 @@
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::sync::mpsc::{channel, Sender, Receiver};
+
+use crate::names::Signature;
+use crate::names::secp256k1::{Signature as KeySignature, PublicKey};
+
+use rusqlite::{Connection, Row, OpenFlags, params, OptionalExtension};
+
+use super::{StorageBackend, Subscriptions, Slot, Entry, Sign, Missive, Cursor, Mailbox};
+
+///Idle reader connections kept open per database
+const READERS: usize = 16;
+///Most writes committed in a single transaction
+const BATCH: usize = 256;
+
+enum Write {
+    Create(PublicKey, Slot, Sender<Slot>),
+    Log(PublicKey, Sign, Sender<(u64, Entry)>),
+    Prune(PublicKey, u64, Sender<u64>),
+    Append(Mailbox, Missive, Sender<Cursor>),
+    Ack(Mailbox, Cursor, Sender<()>),
+    Expire(u64, Sender<()>),
+}
+
+///Reads are served from a pool of read only connections while a single writer thread
+///commits every write queued since its last commit in one transaction
+pub struct Sqlite {
+    path: PathBuf,
+    readers: Mutex<Vec<Connection>>,
+    writer: Sender<Write>,
+    subscriptions: Subscriptions,
+}
+impl Sqlite {
+    pub fn open<P: AsRef<Path>>(path: P) -> Self {
+        if let Some(parent) = path.as_ref().parent() {
+            let _ = std::fs::create_dir_all(parent);
+        }
+        let mut connection = Connection::open(path.as_ref()).unwrap();
+        connection.pragma_update(None, "journal_mode", "WAL").unwrap();
+        connection.pragma_update(None, "synchronous", "NORMAL").unwrap();
+        Self::migrate(&mut connection);
+
+        let (writer, rx) = channel();
+        std::thread::spawn(move || Self::write(connection, rx));
+        Sqlite{path: path.as_ref().to_path_buf(), readers: Mutex::new(vec![]), writer, subscriptions: Subscriptions::default()}
+    }
+
+    ///Brings the database up to the current schema one user_version at a time, each step in its own transaction
+    fn migrate(connection: &mut Connection) {
+        let version = connection.pragma_query_value(None, "user_version", |r| r.get::<_, u32>(0)).unwrap();
+        if version < 1 {
+            let tx = connection.transaction().unwrap();
+            //Databases from before versioning hold the inbox either without cursors, addressed to Names and
+            //read by timestamp, or with postcard timestamps that never compare against the expiry cutoff
+            let (columns, cursor, blob) = tx.query_row(
+                "SELECT COUNT(*), COUNT(CASE WHEN name='cursor' THEN 1 END), COUNT(CASE WHEN name='timestamp' AND type='BLOB' THEN 1 END)
+                 FROM pragma_table_info('inbox')", [], |r| Ok((r.get::<_, i64>(0)?, r.get::<_, i64>(1)?, r.get::<_, i64>(2)?))
+            ).unwrap();
+            let legacy = columns > 0 && (cursor == 0 || blob > 0);
+            if legacy {
+                tx.execute("DROP INDEX if exists inbox_recipient", []).unwrap();
+                tx.execute("ALTER TABLE inbox RENAME TO inbox_legacy", []).unwrap();
+            }
+            tx.execute("CREATE TABLE if not exists private(
+                key TEXT NOT NULL UNIQUE,
+                key_signature BLOB NOT NULL,
+                signature BLOB NOT NULL,
+                timestamp BLOB NOT NULL,
+                payload BLOB NOT NULL
+            );", []).unwrap();
+
+            tx.execute("CREATE TABLE if not exists log(
+                key BLOB NOT NULL,
+                idx INTEGER NOT NULL,
+                signature BLOB NOT NULL,
+                timestamp BLOB NOT NULL,
+                key_signature BLOB NOT NULL,
+                payload BLOB NOT NULL,
+                PRIMARY KEY(key, idx)
+            );", []).unwrap();
+            tx.execute("CREATE TABLE if not exists pruned(
+                key BLOB NOT NULL PRIMARY KEY,
+                below INTEGER NOT NULL
+            );", []).unwrap();
+
+            tx.execute("CREATE TABLE if not exists inbox(
+                cursor INTEGER PRIMARY KEY AUTOINCREMENT,
+                recipient BLOB NOT NULL,
+                timestamp INTEGER NOT NULL,
+                signature BLOB NOT NULL,
+                payload BLOB NOT NULL
+            );", []).unwrap();
+            tx.execute("CREATE INDEX if not exists inbox_recipient ON inbox(recipient, cursor);", []).unwrap();
+            tx.execute("CREATE INDEX if not exists inbox_timestamp ON inbox(timestamp);", []).unwrap();
+            //Set aside rows can not be delivered to a mailbox, they are kept for the operator rather than dropped
+            if legacy {println!("Moved The Unversioned Inbox To inbox_legacy");}
+            tx.pragma_update(None, "user_version", 1).unwrap();
+            tx.commit().unwrap();
+        }
+    }
+
+    fn write(mut connection: Connection, rx: Receiver<Write>) {
+        while let Ok(first) = rx.recv() {
+            let mut batch = vec![first];
+            while batch.len() < BATCH && let Ok(next) = rx.try_recv() {batch.push(next);}
+
+            let tx = connection.transaction().unwrap();
+            let mut replies: Vec<Box<dyn FnOnce()>> = vec![];
+            for write in batch {match write {
+                Write::Create(key, slot, reply) => {
+                    let slot = tx.query_row(
+                        "INSERT INTO private(key, signature, timestamp, key_signature, payload)
+                         VALUES (?1, ?2, ?3, ?4, ?5) ON CONFLICT DO UPDATE SET key=?1
+                         RETURNING signature, key_signature, timestamp, payload;",
+                        params![
+                            postcard::to_allocvec(&key).unwrap(),
+                            postcard::to_allocvec(&slot.0).unwrap(),
+                            postcard::to_allocvec(&slot.1).unwrap(),
+                            postcard::to_allocvec(&slot.2).unwrap(),
+                            slot.3
+                        ], Self::slot
+                    ).unwrap();
+                    replies.push(Box::new(move || {let _ = reply.send(slot);}));
+                },
+                Write::Log(log, sign, reply) => {
+                    let key = postcard::to_allocvec(&log).unwrap();
+                    let index = Self::end(&tx, &key);
+                    let entry = sign(index);
+                    tx.execute(
+                        "INSERT INTO log(key, idx, signature, timestamp, key_signature, payload) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                        params![
+                            key,
+                            index as i64,
+                            postcard::to_allocvec(&entry.0).unwrap(),
+                            postcard::to_allocvec(&entry.1).unwrap(),
+                            postcard::to_allocvec(&entry.2).unwrap(),
+                            entry.3
+                        ]
+                    ).unwrap();
+                    replies.push(Box::new(move || {let _ = reply.send((index, entry));}));
+                },
+                Write::Prune(log, below, reply) => {
+                    let key = postcard::to_allocvec(&log).unwrap();
+                    let pruned = below.min(Self::end(&tx, &key)).max(Self::floor(&tx, &key));
+                    tx.execute(
+                        "INSERT INTO pruned(key, below) VALUES (?1, ?2) ON CONFLICT DO UPDATE SET below=?2", params![key, pruned as i64]
+                    ).unwrap();
+                    tx.execute("DELETE FROM log WHERE key=?1 AND idx<?2", params![key, pruned as i64]).unwrap();
+                    replies.push(Box::new(move || {let _ = reply.send(pruned);}));
+                },
+                Write::Append(recipient, missive, reply) => {
+                    tx.execute(
+                        "INSERT INTO inbox(recipient, timestamp, signature, payload) VALUES (?1, ?2, ?3, ?4)",
+                        params![
+                            postcard::to_allocvec(&recipient).unwrap(),
+                            missive.1 as i64,
+                            postcard::to_allocvec(&missive.0).unwrap(),
+                            missive.2,
+                        ],
+                    ).unwrap();
+                    let cursor = tx.last_insert_rowid() as Cursor;
+                    replies.push(Box::new(move || {let _ = reply.send(cursor);}));
+                },
+                Write::Ack(recipient, cursor, reply) => {
+                    tx.execute(
+                        "DELETE FROM inbox WHERE recipient=?1 AND cursor<=?2",
+                        params![postcard::to_allocvec(&recipient).unwrap(), cursor as i64]
+                    ).unwrap();
+                    replies.push(Box::new(move || {let _ = reply.send(());}));
+                },
+                Write::Expire(before, reply) => {
+                    tx.execute("DELETE FROM inbox WHERE timestamp<?1", [before as i64]).unwrap();
+                    replies.push(Box::new(move || {let _ = reply.send(());}));
+                }
+            }}
+            tx.commit().unwrap();
+            replies.into_iter().for_each(|reply| reply());
+        }
+    }
+
+    ///One past the last entry of the log, or where it was pruned to if that is further
+    fn end(connection: &Connection, key: &[u8]) -> u64 {
+        let end = connection.query_row("SELECT COALESCE(MAX(idx) + 1, 0) FROM log WHERE key=?1", [key], |r| r.get::<_, i64>(0)).unwrap() as u64;
+        end.max(Self::floor(connection, key))
+    }
+
+    fn floor(connection: &Connection, key: &[u8]) -> u64 {
+        connection.query_row("SELECT below FROM pruned WHERE key=?1", [key], |r| r.get::<_, i64>(0)).optional().unwrap().unwrap_or_default() as u64
+    }
+
+    fn reader<R>(&self, f: impl FnOnce(&Connection) -> R) -> R {
+        let connection = self.readers.lock().unwrap().pop().unwrap_or_else(||
+            Connection::open_with_flags(&self.path, OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX).unwrap()
+        );
+        let result = f(&connection);
+        let mut readers = self.readers.lock().unwrap();
+        if readers.len() < READERS {readers.push(connection);}
+        result
+    }
+
+    fn slot(row: &Row) -> rusqlite::Result<Slot> {Ok((
+        postcard::from_bytes::<Signature>(&row.get::<_, Vec<u8>>("signature")?).unwrap(),
+        postcard::from_bytes::<u64>(&row.get::<_, Vec<u8>>("timestamp")?).unwrap(),
+        postcard::from_bytes::<KeySignature>(&row.get::<_, Vec<u8>>("key_signature")?).unwrap(),
+        row.get::<_, Vec<u8>>("payload")?
+    ))}
+}
+
+impl StorageBackend for Sqlite {
+    fn create(&self, key: PublicKey, slot: Slot) -> Slot {
+        let (tx, rx) = channel();
+        self.writer.send(Write::Create(key, slot, tx)).unwrap();
+        rx.recv().unwrap()
+    }
+
+    fn read(&self, key: &PublicKey) -> Option<Slot> {
+        self.reader(|connection| connection.query_row(
+            "SELECT signature, timestamp, key_signature, payload FROM private WHERE key=?1",
+            [postcard::to_allocvec(key).unwrap()], Self::slot
+        ).optional().unwrap())
+    }
+
+    ///Every key is read on one connection with one prepared statement
+    fn read_many(&self, keys: &[PublicKey]) -> Vec<Option<Slot>> {
+        self.reader(|connection| {
+            let mut statement = connection.prepare_cached("SELECT signature, timestamp, key_signature, payload FROM private WHERE key=?1").unwrap();
+            keys.iter().map(|key| statement.query_row([postcard::to_allocvec(key).unwrap()], Self::slot).optional().unwrap()).collect()
+        })
+    }
+
+    fn log(&self, log: PublicKey, sign: Sign) -> (u64, Entry) {
+        let (tx, rx) = channel();
+        self.writer.send(Write::Log(log, sign, tx)).unwrap();
+        rx.recv().unwrap()
+    }
+
+    fn entry(&self, log: &PublicKey, index: u64) -> Result<Option<Entry>, u64> {
+        self.reader(|connection| {
+            let (tx, key) = (connection.unchecked_transaction().unwrap(), postcard::to_allocvec(log).unwrap());
+            let pruned = Self::floor(&tx, &key);
+            if index < pruned {return Err(pruned);}
+            Ok(tx.query_row(
+                "SELECT signature, timestamp, key_signature, payload FROM log WHERE key=?1 AND idx=?2",
+                params![key, index as i64], Self::slot
+            ).optional().unwrap())
+        })
+    }
+
+    fn prune(&self, log: PublicKey, below: u64) -> u64 {
+        let (tx, rx) = channel();
+        self.writer.send(Write::Prune(log, below, tx)).unwrap();
+        rx.recv().unwrap()
+    }
+
+    fn append(&self, recipient: Mailbox, missive: Missive) -> Cursor {
+        let (tx, rx) = channel();
+        self.writer.send(Write::Append(recipient, missive, tx)).unwrap();
+        rx.recv().unwrap()
+    }
+
+    fn query(&self, recipient: &Mailbox, after: Cursor, limit: usize) -> Vec<(Cursor, Missive)> {
+        self.reader(|connection| connection.prepare_cached(
+            "SELECT cursor, signature, timestamp, payload FROM inbox WHERE recipient=?1 AND cursor>?2 ORDER BY cursor LIMIT ?3"
+        ).unwrap().query_map(
+            params![postcard::to_allocvec(recipient).unwrap(), after as i64, limit as i64], |r| Ok((
+                r.get::<_, i64>("cursor")? as Cursor, (
+                    postcard::from_bytes::<Signature>(&r.get::<_, Vec<u8>>("signature")?).unwrap(),
+                    r.get::<_, i64>("timestamp")? as u64,
+                    r.get::<_, Vec<u8>>("payload")?,
+                )
+            ))
+        ).unwrap().collect::<Result<Vec<_>, rusqlite::Error>>().unwrap())
+    }
+
+    fn ack(&self, recipient: &Mailbox, cursor: Cursor) {
+        let (tx, rx) = channel();
+        self.writer.send(Write::Ack(*recipient, cursor, tx)).unwrap();
+        rx.recv().unwrap()
+    }
+
+    fn expire(&self, before: u64) {
+        let (tx, rx) = channel();
+        self.writer.send(Write::Expire(before, tx)).unwrap();
+        rx.recv().unwrap()
+    }
+
+    fn subscriptions(&self) -> &Subscriptions {&self.subscriptions}
+}